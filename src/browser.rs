@@ -0,0 +1,528 @@
+//! Full-screen browser for the conversations in a [`Store`], either while
+//! they are being recorded or loaded from a capture file.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, queue};
+
+use crate::capture::{Content, Conversation, Item, Store};
+use crate::formatter::{dump_binary, dump_text, Formatter, Side, Timestamp};
+
+const HELP_LIST: &str = "↑↓ select  Enter open  / search  n N next/previous match  q quit";
+const HELP_CONVERSATION: &str =
+    "↑↓ PgUp PgDn scroll  Enter expand  x hex  / search  n N next/previous match  Esc back  q quit";
+
+/// Run the browser until the user quits or `should_stop` returns true.
+pub fn run(store: Arc<Mutex<Store>>, mut should_stop: impl FnMut() -> bool) -> io::Result<()> {
+    let mut out = io::stdout();
+    terminal::enable_raw_mode()?;
+    let _restore = Restore;
+    queue!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+    let mut browser = Browser::new(store);
+    let mut generation = None;
+    let mut dirty = true;
+    while !should_stop() {
+        let current = browser.store.lock().unwrap().generation;
+        if generation != Some(current) {
+            generation = Some(current);
+            browser.refresh();
+            dirty = true;
+        }
+        if dirty {
+            browser.draw(&mut out)?;
+            dirty = false;
+        }
+        if !event::poll(Duration::from_millis(200))? {
+            continue;
+        }
+        match event::read()? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => {
+                if !browser.key(key) {
+                    break;
+                }
+                dirty = true;
+            }
+            Event::Resize(..) => dirty = true,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Puts the terminal back the way it was, also when panicking.
+struct Restore;
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let _ = queue!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = io::stdout().flush();
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expansion {
+    Text,
+    Hex,
+}
+
+/// One line on the screen in the conversation view.
+struct Line {
+    /// Index of the item it belongs to
+    item: usize,
+    side: Side,
+    text: String,
+    header: bool,
+}
+
+struct Browser {
+    store: Arc<Mutex<Store>>,
+    /// Index of the conversation being viewed
+    open: Option<usize>,
+    list_cursor: usize,
+    list_top: usize,
+    lines: Vec<Line>,
+    cursor: usize,
+    top: usize,
+    expanded: HashMap<usize, Expansion>,
+    /// The search being typed
+    input: Option<String>,
+    search: String,
+    status: String,
+}
+
+impl Browser {
+    fn new(store: Arc<Mutex<Store>>) -> Browser {
+        Browser {
+            store,
+            open: None,
+            list_cursor: 0,
+            list_top: 0,
+            lines: vec![],
+            cursor: 0,
+            top: 0,
+            expanded: HashMap::new(),
+            input: None,
+            search: String::new(),
+            status: String::new(),
+        }
+    }
+
+    /// Rebuild the lines of the open conversation. Keeps following the end
+    /// if the cursor was there.
+    fn refresh(&mut self) {
+        let Some(open) = self.open else {
+            return;
+        };
+        let at_end = self.cursor + 1 >= self.lines.len();
+        let store = self.store.lock().unwrap();
+        let conversation = &store.conversations[open];
+        self.lines = render(conversation, &self.expanded);
+        drop(store);
+        if at_end {
+            self.cursor = self.lines.len().saturating_sub(1);
+        }
+        self.cursor = self.cursor.min(self.lines.len().saturating_sub(1));
+    }
+
+    /// Returns false when the user wants to quit.
+    fn key(&mut self, key: KeyEvent) -> bool {
+        if let Some(input) = &mut self.input {
+            match key.code {
+                KeyCode::Enter => {
+                    self.search = self.input.take().unwrap();
+                    self.find(true, false);
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+            return true;
+        }
+        self.status.clear();
+        let page = terminal::size()
+            .map_or(20, |(_, h)| h as usize)
+            .saturating_sub(3)
+            .max(1);
+        let ctrl_c =
+            key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c');
+        match key.code {
+            KeyCode::Char('q') => return false,
+            _ if ctrl_c => return false,
+            KeyCode::Char('/') => self.input = Some(String::new()),
+            KeyCode::Char('n') => self.find(true, true),
+            KeyCode::Char('N') => self.find(false, true),
+            KeyCode::Up | KeyCode::Char('k') => self.move_cursor(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_cursor(1),
+            KeyCode::PageUp => self.move_cursor(-(page as isize)),
+            KeyCode::PageDown | KeyCode::Char(' ') => self.move_cursor(page as isize),
+            KeyCode::Home | KeyCode::Char('g') => self.move_cursor(isize::MIN / 2),
+            KeyCode::End | KeyCode::Char('G') => self.move_cursor(isize::MAX / 2),
+            KeyCode::Enter | KeyCode::Right if self.open.is_none() => self.open_conversation(),
+            KeyCode::Esc | KeyCode::Left | KeyCode::Backspace if self.open.is_some() => {
+                self.open = None;
+                self.lines.clear();
+            }
+            KeyCode::Enter => self.toggle(Expansion::Text),
+            KeyCode::Char('x') => self.toggle(Expansion::Hex),
+            _ => {}
+        }
+        true
+    }
+
+    fn move_cursor(&mut self, delta: isize) {
+        let (cursor, len) = match self.open {
+            Some(_) => (&mut self.cursor, self.lines.len()),
+            None => (
+                &mut self.list_cursor,
+                self.store.lock().unwrap().conversations.len(),
+            ),
+        };
+        let max = len.saturating_sub(1) as isize;
+        *cursor = (*cursor as isize).saturating_add(delta).clamp(0, max) as usize;
+    }
+
+    fn open_conversation(&mut self) {
+        if self.list_cursor >= self.store.lock().unwrap().conversations.len() {
+            return;
+        }
+        self.open = Some(self.list_cursor);
+        self.expanded.clear();
+        self.lines.clear();
+        self.cursor = 0;
+        self.top = 0;
+        self.refresh();
+        self.cursor = 0;
+    }
+
+    /// Expand or collapse the message under the cursor.
+    fn toggle(&mut self, how: Expansion) {
+        let Some(line) = self.lines.get(self.cursor) else {
+            return;
+        };
+        let item = line.item;
+        if self.expanded.get(&item) == Some(&how) {
+            self.expanded.remove(&item);
+        } else {
+            self.expanded.insert(item, how);
+        }
+        self.refresh();
+        // Stay on the header of the item
+        self.cursor = self.lines.iter().position(|l| l.item == item).unwrap_or(0);
+    }
+
+    /// Move to the next or previous conversation or message containing the
+    /// search text.
+    fn find(&mut self, forward: bool, skip_current: bool) {
+        if self.search.is_empty() {
+            return;
+        }
+        let needle = self.search.to_lowercase();
+        let store = self.store.lock().unwrap();
+        let found = match self.open {
+            None => {
+                let matches = |i: usize| conversation_matches(&store.conversations[i], &needle);
+                search(
+                    self.list_cursor,
+                    store.conversations.len(),
+                    forward,
+                    skip_current,
+                    matches,
+                )
+            }
+            Some(open) => {
+                let items = &store.conversations[open].items;
+                let current = self.lines.get(self.cursor).map_or(0, |l| l.item);
+                search(current, items.len(), forward, skip_current, |i| {
+                    item_matches(&items[i], &needle)
+                })
+            }
+        };
+        drop(store);
+        match (found, self.open) {
+            (None, _) => self.status = format!("not found: {}", self.search),
+            (Some(i), None) => self.list_cursor = i,
+            (Some(i), Some(_)) => {
+                self.cursor = self.lines.iter().position(|l| l.item == i).unwrap_or(0)
+            }
+        }
+    }
+
+    fn draw(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let (width, height) = (width as usize, height as usize);
+        let body = height.saturating_sub(2);
+        let store = self.store.lock().unwrap();
+
+        let (title, help, cursor, top) = match self.open {
+            None => (
+                format!("{} connections", store.conversations.len()),
+                HELP_LIST,
+                &mut self.list_cursor,
+                &mut self.list_top,
+            ),
+            Some(open) => {
+                let c = &store.conversations[open];
+                (
+                    format!(
+                        "connection {}: {} to {}, {} messages",
+                        c.id,
+                        c.client,
+                        c.server,
+                        c.messages()
+                    ),
+                    HELP_CONVERSATION,
+                    &mut self.cursor,
+                    &mut self.top,
+                )
+            }
+        };
+        if *cursor < *top {
+            *top = *cursor;
+        } else if *cursor >= *top + body {
+            *top = *cursor + 1 - body;
+        }
+        let (cursor, top) = (*cursor, *top);
+
+        queue!(out, cursor::MoveTo(0, 0), terminal::Clear(ClearType::All))?;
+        queue!(
+            out,
+            SetAttribute(Attribute::Bold),
+            Print(fit(&title, width)),
+            SetAttribute(Attribute::Reset)
+        )?;
+        for row in 0..body {
+            let i = top + row;
+            queue!(out, cursor::MoveTo(0, row as u16 + 1))?;
+            let (text, color) = match self.open {
+                None => match store.conversations.get(i) {
+                    Some(c) => (list_line(c), None),
+                    None => break,
+                },
+                Some(_) => match self.lines.get(i) {
+                    Some(line) => (line.text.clone(), Some((line.side, line.header))),
+                    None => break,
+                },
+            };
+            if let Some((side, header)) = color {
+                let color = match side {
+                    Side::Client => Color::Green,
+                    Side::Server => Color::Cyan,
+                };
+                queue!(out, SetForegroundColor(color))?;
+                if header {
+                    queue!(out, SetAttribute(Attribute::Bold))?;
+                }
+            }
+            if i == cursor {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            }
+            queue!(
+                out,
+                Print(fit(&text, width)),
+                SetAttribute(Attribute::Reset),
+                ResetColor
+            )?;
+        }
+        let footer = match &self.input {
+            Some(input) => format!("/{input}"),
+            None if !self.status.is_empty() => self.status.clone(),
+            None => help.to_string(),
+        };
+        queue!(
+            out,
+            cursor::MoveTo(0, height.saturating_sub(1) as u16),
+            Print(fit(&footer, width))
+        )?;
+        out.flush()
+    }
+}
+
+/// The next index from `start` for which `matches` holds, wrapping around.
+fn search(
+    start: usize,
+    len: usize,
+    forward: bool,
+    skip_current: bool,
+    matches: impl Fn(usize) -> bool,
+) -> Option<usize> {
+    if len == 0 {
+        return None;
+    }
+    let first = usize::from(skip_current);
+    (first..first + len)
+        .map(|step| match forward {
+            true => (start + step) % len,
+            false => (start + len - step % len) % len,
+        })
+        .find(|&i| matches(i))
+}
+
+fn conversation_matches(conversation: &Conversation, needle: &str) -> bool {
+    conversation.client.to_lowercase().contains(needle)
+        || conversation.server.to_lowercase().contains(needle)
+        || conversation
+            .items
+            .iter()
+            .any(|item| item_matches(item, needle))
+}
+
+fn item_matches(item: &Item, needle: &str) -> bool {
+    match &item.content {
+        Content::Message(data) => String::from_utf8_lossy(data)
+            .to_lowercase()
+            .contains(needle),
+        Content::Note(text) => text.to_lowercase().contains(needle),
+    }
+}
+
+fn list_line(c: &Conversation) -> String {
+    let state = match c.closed {
+        [true, true] => "closed",
+        _ => "open",
+    };
+    format!(
+        "{id:>5}  {time:.3}  {client} to {server}  {n} messages, {state}",
+        id = c.id,
+        time = Timestamp(c.started),
+        client = c.client,
+        server = c.server,
+        n = c.messages(),
+    )
+}
+
+/// All lines of the conversation view.
+fn render(conversation: &Conversation, expanded: &HashMap<usize, Expansion>) -> Vec<Line> {
+    let mut lines = vec![];
+    for (i, item) in conversation.items.iter().enumerate() {
+        let offset = item.time.saturating_sub(conversation.started) as f64 / 1e6;
+        let indent = match item.side {
+            Side::Client => "",
+            Side::Server => "        ",
+        };
+        let header = match &item.content {
+            Content::Message(data) => {
+                let (kind, preview) = match std::str::from_utf8(data) {
+                    Ok(text) => ("text", text.lines().next().unwrap_or_default()),
+                    Err(_) => ("binary", ""),
+                };
+                let preview: String = preview
+                    .chars()
+                    .map(|c| if c.is_control() { '·' } else { c })
+                    .collect();
+                format!(
+                    "{offset:>10.3}s {indent}{side} {kind}, {n} bytes  {preview}",
+                    side = item.side,
+                    n = data.len()
+                )
+            }
+            Content::Note(text) => {
+                format!("{offset:>10.3}s {indent}{side} • {text}", side = item.side)
+            }
+        };
+        lines.push(Line {
+            item: i,
+            side: item.side,
+            text: header,
+            header: true,
+        });
+
+        let (Some(&how), Content::Message(data)) = (expanded.get(&i), &item.content) else {
+            continue;
+        };
+        let mut dump = Dump::default();
+        let _ = match (how, std::str::from_utf8(data)) {
+            (Expansion::Text, Ok(text)) => dump_text(&mut dump, text),
+            _ => dump_binary(&mut dump, data),
+        };
+        for text in dump.finish() {
+            lines.push(Line {
+                item: i,
+                side: item.side,
+                text: format!("            {indent}│ {text}"),
+                header: false,
+            });
+        }
+    }
+    lines
+}
+
+/// Truncate or pad to exactly `width` characters.
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted: String = text.chars().take(width).collect();
+    let n = fitted.chars().count();
+    fitted.extend(std::iter::repeat_n(' ', width - n));
+    fitted
+}
+
+/// Collects what [`dump_text`] and [`dump_binary`] write, line by line.
+#[derive(Default)]
+struct Dump {
+    lines: Vec<String>,
+    current: Vec<u8>,
+}
+
+impl Dump {
+    fn finish(mut self) -> Vec<String> {
+        if !self.current.is_empty() {
+            self.lines
+                .push(String::from_utf8_lossy(&self.current).into_owned());
+        }
+        self.lines
+    }
+}
+
+impl io::Write for Dump {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &b in buf {
+            if b == b'\n' {
+                let line = std::mem::take(&mut self.current);
+                self.lines.push(String::from_utf8_lossy(&line).into_owned());
+            } else {
+                self.current.push(b);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Formatter for Dump {
+    fn connected(
+        &mut self,
+        _local: &dyn fmt::Display,
+        _remote: &dyn fmt::Display,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn proxy_message(&mut self, _message: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn message(&mut self, _side: Side, _message: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn start_block(&mut self, _side: Side, _message: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn end_block(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,468 @@
+//! Recording what happens on each connection, to browse it live or to save
+//! it to a capture file and browse it later.
+//!
+//! A capture file starts with [`MAGIC`], followed by records consisting of
+//! a kind byte, the connection id (u64), the time in microseconds since the
+//! epoch (u64), a side byte, the payload length (u32) and the payload.
+//! Numbers are little endian.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::formatter::{Side, Timestamp};
+use crate::observers::{Blocks, Frame};
+use crate::proxy::{self, Observer};
+
+const MAGIC: &[u8] = b"monetproxy capture 1\n";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Connected {
+        client: String,
+        server: String,
+    },
+    Data(Side, Vec<u8>),
+    /// The side closed its connection or failed, with a description
+    Closed(Side, String),
+    Remark(Side, String),
+}
+
+impl Event {
+    fn kind(&self) -> u8 {
+        match self {
+            Event::Connected { .. } => 1,
+            Event::Data(..) => 2,
+            Event::Closed(..) => 3,
+            Event::Remark(..) => 4,
+        }
+    }
+
+    fn side(&self) -> Side {
+        match self {
+            Event::Connected { .. } => Side::Client,
+            Event::Data(side, _) | Event::Closed(side, _) | Event::Remark(side, _) => *side,
+        }
+    }
+
+    fn decode(kind: u8, side: Side, payload: Vec<u8>) -> io::Result<Event> {
+        let text = || String::from_utf8_lossy(&payload).into_owned();
+        let event = match kind {
+            1 => {
+                let text = text();
+                let (client, server) = text.split_once('\n').unwrap_or((&text, ""));
+                Event::Connected {
+                    client: client.to_string(),
+                    server: server.to_string(),
+                }
+            }
+            2 => Event::Data(side, payload),
+            3 => Event::Closed(side, text()),
+            4 => Event::Remark(side, text()),
+            _ => return Err(bad_capture(format!("unknown record kind {kind}"))),
+        };
+        Ok(event)
+    }
+}
+
+/// How often the capture file is flushed while recording.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How much message data the live browser keeps, older messages are
+/// forgotten first.
+pub const LIVE_LIMIT: usize = 256 << 20;
+
+/// Passes everything that happens to the store and the capture file.
+pub struct Recorder {
+    file: Option<Mutex<BufWriter<File>>>,
+    store: Option<Arc<Mutex<Store>>>,
+}
+
+impl Recorder {
+    /// The capture file is written in the background, see [`Recorder::flush`].
+    pub fn new(file: Option<File>, store: Option<Arc<Mutex<Store>>>) -> io::Result<Arc<Recorder>> {
+        let file = match file {
+            Some(mut f) => {
+                f.write_all(MAGIC)?;
+                Some(Mutex::new(BufWriter::with_capacity(1 << 16, f)))
+            }
+            None => None,
+        };
+        let recorder = Arc::new(Recorder { file, store });
+        if recorder.file.is_some() {
+            let weak = Arc::downgrade(&recorder);
+            proxy::spawn_worker("recorder", move || loop {
+                thread::sleep(FLUSH_INTERVAL);
+                match weak.upgrade() {
+                    Some(recorder) => recorder.flush()?,
+                    None => return Ok(()),
+                }
+            });
+        }
+        Ok(recorder)
+    }
+
+    pub fn record(&self, conn: u64, event: Event) -> io::Result<()> {
+        let Timestamp(micros) = Timestamp::now();
+        if let Some(file) = &self.file {
+            write_record(&mut *file.lock().unwrap(), conn, micros, &event)?;
+        }
+        if let Some(store) = &self.store {
+            store.lock().unwrap().apply(conn, micros, event);
+        }
+        Ok(())
+    }
+
+    /// Write out what has been recorded so far. Happens every
+    /// [`FLUSH_INTERVAL`] by itself.
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.lock().unwrap().flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn write_record(w: &mut impl Write, conn: u64, micros: u64, event: &Event) -> io::Result<()> {
+    let connected;
+    let payload: &[u8] = match event {
+        Event::Connected { client, server } => {
+            connected = format!("{client}\n{server}");
+            connected.as_bytes()
+        }
+        Event::Data(_, data) => data,
+        Event::Closed(_, text) | Event::Remark(_, text) => text.as_bytes(),
+    };
+    let side = match event.side() {
+        Side::Client => 0u8,
+        Side::Server => 1,
+    };
+    w.write_all(&[event.kind()])?;
+    w.write_all(&conn.to_le_bytes())?;
+    w.write_all(&micros.to_le_bytes())?;
+    w.write_all(&[side])?;
+    w.write_all(&(payload.len() as u32).to_le_bytes())?;
+    w.write_all(payload)
+}
+
+fn bad_capture(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Wraps another observer and records everything it sees.
+pub struct Recording<I> {
+    inner: I,
+    recorder: Option<Arc<Recorder>>,
+    conn: u64,
+    side: Side,
+}
+
+impl<I: Observer> Recording<I> {
+    pub fn new(inner: I, recorder: Option<Arc<Recorder>>, conn: u64, side: Side) -> Recording<I> {
+        Recording {
+            inner,
+            recorder,
+            conn,
+            side,
+        }
+    }
+
+    fn record(&self, event: Event) -> io::Result<()> {
+        match &self.recorder {
+            Some(recorder) => recorder.record(self.conn, event),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<I: Observer> Observer for Recording<I> {
+    fn on_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.record(Event::Data(self.side, data.to_vec()))?;
+        self.inner.on_data(data)
+    }
+
+    fn on_close(&mut self) -> io::Result<()> {
+        self.record(Event::Closed(
+            self.side,
+            "closed its side of the connection".to_string(),
+        ))?;
+        self.inner.on_close()
+    }
+
+    fn on_error(&mut self, while_writing: bool, err: &io::Error) -> io::Result<()> {
+        let action = if while_writing {
+            "error writing"
+        } else {
+            "error reading"
+        };
+        self.record(Event::Closed(self.side, format!("{action}: {err}")))?;
+        self.inner.on_error(while_writing, err)
+    }
+
+    fn on_unix0(&mut self, data: &[u8], message: Option<&str>) -> io::Result<()> {
+        self.inner.on_unix0(data, message)
+    }
+
+    fn message(&mut self, message: &str) -> io::Result<()> {
+        self.inner.message(message)
+    }
+
+    fn on_remark(&mut self, message: &str) -> io::Result<()> {
+        self.record(Event::Remark(self.side, message.to_string()))?;
+        self.inner.on_remark(message)
+    }
+
+    fn wants_data(&self) -> bool {
+        self.recorder.is_some() || self.inner.wants_data()
+    }
+
+    fn on_forwarded(&mut self, nbytes: usize) -> io::Result<()> {
+        self.inner.on_forwarded(nbytes)
+    }
+}
+
+/// Everything that has been recorded, organized for browsing.
+#[derive(Default)]
+pub struct Store {
+    pub conversations: Vec<Conversation>,
+    /// Incremented on every change
+    pub generation: u64,
+    /// Keep at most this many bytes of message data
+    limit: Option<usize>,
+    /// Bytes of message data kept
+    bytes: usize,
+    /// The messages kept, oldest first, as conversation and item index
+    kept: VecDeque<(usize, usize)>,
+}
+
+pub struct Conversation {
+    pub id: u64,
+    pub client: String,
+    pub server: String,
+    /// Microseconds since the epoch
+    pub started: u64,
+    pub items: Vec<Item>,
+    /// Which sides have closed
+    pub closed: [bool; 2],
+    blocks: [Blocks; 2],
+    partial: [Vec<u8>; 2],
+    /// Whether the message being received is too big to keep in full
+    cut: [bool; 2],
+}
+
+pub struct Item {
+    pub side: Side,
+    /// Microseconds since the epoch
+    pub time: u64,
+    pub content: Content,
+}
+
+pub enum Content {
+    /// The payload of a complete message
+    Message(Vec<u8>),
+    /// Something the proxy has to say about it
+    Note(String),
+}
+
+impl Store {
+    /// Forgets the oldest messages when they take more than `limit` bytes.
+    pub fn bounded(limit: usize) -> Store {
+        Store {
+            limit: Some(limit),
+            ..Store::default()
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Store> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = vec![0; MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(bad_capture(format!(
+                "{} is not a capture file",
+                path.display()
+            )));
+        }
+        let mut store = Store::default();
+        let mut header = [0u8; 22];
+        loop {
+            match r.read_exact(&mut header) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                other => other?,
+            }
+            let kind = header[0];
+            let conn = u64::from_le_bytes(header[1..9].try_into().unwrap());
+            let micros = u64::from_le_bytes(header[9..17].try_into().unwrap());
+            let side = if header[17] == 0 {
+                Side::Client
+            } else {
+                Side::Server
+            };
+            let len = u32::from_le_bytes(header[18..22].try_into().unwrap());
+            let mut payload = vec![0; len as usize];
+            match r.read_exact(&mut payload) {
+                // The proxy may still be writing it
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                other => other?,
+            }
+            store.apply(conn, micros, Event::decode(kind, side, payload)?);
+        }
+        Ok(store)
+    }
+
+    pub fn apply(&mut self, conn: u64, micros: u64, event: Event) {
+        self.generation += 1;
+        if let Event::Connected { client, server } = event {
+            self.conversations.push(Conversation {
+                id: conn,
+                client,
+                server,
+                started: micros,
+                items: vec![],
+                closed: [false; 2],
+                blocks: Default::default(),
+                partial: Default::default(),
+                cut: [false; 2],
+            });
+            return;
+        }
+        let Some(c) = self.conversations.iter().rposition(|c| c.id == conn) else {
+            return;
+        };
+        let conversation = &mut self.conversations[c];
+        match event {
+            Event::Connected { .. } => unreachable!(),
+            Event::Data(side, data) => {
+                let limit = self.limit.unwrap_or(usize::MAX);
+                let start = conversation.items.len();
+                self.bytes += conversation.data(side, micros, &data, limit);
+                if self.limit.is_some() {
+                    for (i, item) in conversation.items.iter().enumerate().skip(start) {
+                        if matches!(item.content, Content::Message(_)) {
+                            self.kept.push_back((c, i));
+                        }
+                    }
+                    self.forget(limit);
+                }
+            }
+            Event::Closed(side, text) => {
+                conversation.closed[side as usize] = true;
+                conversation.note(side, micros, text);
+            }
+            Event::Remark(side, text) => conversation.note(side, micros, text),
+        }
+    }
+
+    /// Replace the oldest messages by a note until the rest fits. Messages
+    /// still being received are never forgotten.
+    fn forget(&mut self, limit: usize) {
+        while self.bytes > limit {
+            let Some((c, i)) = self.kept.pop_front() else {
+                return;
+            };
+            let item = &mut self.conversations[c].items[i];
+            if let Content::Message(payload) = &item.content {
+                let n = payload.len();
+                item.content = Content::Note(format!("{n} byte message forgotten to save memory"));
+                self.bytes -= n;
+            }
+        }
+    }
+}
+
+impl Conversation {
+    /// Keeps at most `limit` bytes of each message. Returns how many bytes
+    /// have been added.
+    fn data(&mut self, side: Side, micros: u64, data: &[u8], limit: usize) -> usize {
+        let i = side as usize;
+        let mut added = 0;
+        for (_, frame) in self.blocks[i].feed(data) {
+            match frame {
+                Frame::Payload(range) => {
+                    let room = limit.saturating_sub(self.partial[i].len());
+                    let kept = range.start..range.end.min(range.start.saturating_add(room));
+                    self.cut[i] |= kept.len() < range.len();
+                    added += kept.len();
+                    self.partial[i].extend_from_slice(&data[kept]);
+                }
+                Frame::End => {
+                    let payload = std::mem::take(&mut self.partial[i]);
+                    self.items.push(Item {
+                        side,
+                        time: micros,
+                        content: Content::Message(payload),
+                    });
+                    if std::mem::take(&mut self.cut[i]) {
+                        self.note(side, micros, format!("message cut off after {limit} bytes"));
+                    }
+                }
+                Frame::Start | Frame::Header(_) | Frame::BlockEnd(_) => {}
+            }
+        }
+        added
+    }
+
+    fn note(&mut self, side: Side, micros: u64, text: String) {
+        self.items.push(Item {
+            side,
+            time: micros,
+            content: Content::Note(text),
+        });
+    }
+
+    pub fn messages(&self) -> usize {
+        self.items
+            .iter()
+            .filter(|item| matches!(item.content, Content::Message(_)))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tamper::frame;
+
+    fn contents(store: &Store) -> Vec<String> {
+        let items = &store.conversations[0].items;
+        items
+            .iter()
+            .map(|item| match &item.content {
+                Content::Message(payload) => String::from_utf8_lossy(payload).into_owned(),
+                Content::Note(text) => format!("• {text}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bounded_store() {
+        let mut store = Store::bounded(10);
+        let (client, server) = ("c".to_string(), "s".to_string());
+        store.apply(1, 0, Event::Connected { client, server });
+        for message in ["sone", "stwo", "sthree"] {
+            store.apply(1, 0, Event::Data(Side::Client, frame(message.as_bytes())));
+        }
+        assert_eq!(
+            contents(&store),
+            [
+                "• 4 byte message forgotten to save memory",
+                "stwo",
+                "sthree"
+            ]
+        );
+        store.apply(1, 0, Event::Data(Side::Server, frame(b"0123456789abc")));
+        assert_eq!(
+            &contents(&store)[1..],
+            [
+                "• 4 byte message forgotten to save memory",
+                "• 6 byte message forgotten to save memory",
+                "0123456789",
+                "• message cut off after 10 bytes"
+            ]
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::formatter::Side;
+use crate::network::Closer;
+
+/// Keeps track of the proxied connections so they can be counted,
+/// and closed from elsewhere, for example when shutting down.
+pub struct Connections {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    shutting_down: bool,
+    next_id: u64,
+    active: HashMap<u64, Weak<Connection>>,
+    totals: Stats,
+    max_admitted: Option<usize>,
+    admitted: usize,
+    /// Number of admitted connections per [`Gate`]
+    gates: Vec<usize>,
+}
+
+/// What to do with clients that arrive when the maximum number of connections
+/// has been reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Send them an error message and close the connection
+    Refuse,
+    /// Leave them waiting in the listen queue until a connection closes
+    Queue,
+}
+
+/// Admission control for a single listener, see [`Connections::admit`].
+#[derive(Debug)]
+pub struct Gate {
+    id: usize,
+    max: Option<usize>,
+    pub when_full: WhenFull,
+}
+
+/// Proof of admission, releases the slot when dropped.
+pub struct Ticket {
+    owner: Arc<Connections>,
+    gate: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    pub accepted: u64,
+    pub killed: u64,
+    pub refused: u64,
+    pub from_client: u64,
+    pub from_server: u64,
+}
+
+impl Connections {
+    pub fn new() -> Arc<Connections> {
+        Arc::new(Connections {
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        })
+    }
+
+    /// Limit the total number of connections, over all listeners.
+    pub fn set_limit(&self, max: Option<usize>) {
+        self.state.lock().unwrap().max_admitted = max;
+    }
+
+    /// Create a [`Gate`] for a listener that admits at most `max` connections.
+    pub fn gate(&self, max: Option<usize>, when_full: WhenFull) -> Gate {
+        let mut state = self.state.lock().unwrap();
+        state.gates.push(0);
+        Gate {
+            id: state.gates.len() - 1,
+            max,
+            when_full,
+        }
+    }
+
+    /// Claim a slot for a new connection on the given gate. If `wait` is set,
+    /// block until a slot is available, otherwise return why there is none.
+    /// Also fails when shutting down.
+    pub fn admit(self: &Arc<Self>, gate: &Gate, wait: bool) -> Result<Ticket, String> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.shutting_down {
+                return Err("the proxy is shutting down".to_string());
+            }
+            let full = match (state.max_admitted, gate.max) {
+                (Some(max), _) if state.admitted >= max => {
+                    format!("too many connections, the proxy allows at most {max}")
+                }
+                (_, Some(max)) if state.gates[gate.id] >= max => {
+                    format!("too many connections, this address allows at most {max}")
+                }
+                _ => break,
+            };
+            if !wait {
+                if gate.when_full == WhenFull::Refuse {
+                    state.totals.refused += 1;
+                }
+                return Err(full);
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+        state.admitted += 1;
+        state.gates[gate.id] += 1;
+        Ok(Ticket {
+            owner: Arc::clone(self),
+            gate: gate.id,
+        })
+    }
+
+    /// Register a new connection. It is unregistered when the last
+    /// reference to the returned [`Connection`] is dropped.
+    pub fn register(self: &Arc<Self>, closers: Vec<Closer>, ticket: Ticket) -> Arc<Connection> {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        state.totals.accepted += 1;
+        let conn = Arc::new(Connection {
+            id: state.next_id,
+            owner: Arc::clone(self),
+            closers,
+            _ticket: ticket,
+            kill_reason: Mutex::new(None),
+            started: Instant::now(),
+            last_activity: AtomicU64::new(0),
+            from_client: AtomicU64::new(0),
+            from_server: AtomicU64::new(0),
+        });
+        state.active.insert(conn.id, Arc::downgrade(&conn));
+        conn
+    }
+
+    pub fn begin_shutdown(&self) {
+        self.state.lock().unwrap().shutting_down = true;
+        // Wake up the listeners waiting in admit()
+        self.changed.notify_all();
+    }
+
+    pub fn shutting_down(&self) -> bool {
+        self.state.lock().unwrap().shutting_down
+    }
+
+    pub fn active(&self) -> usize {
+        self.state.lock().unwrap().active.len()
+    }
+
+    /// Wait until no connections are active anymore.
+    /// Returns false if that didn't happen within the timeout.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while !state.active.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    /// Close all active connections, returns how many there were.
+    pub fn kill_all(&self, reason: &str) -> usize {
+        let victims: Vec<_> = {
+            let state = self.state.lock().unwrap();
+            state.active.values().filter_map(Weak::upgrade).collect()
+        };
+        for conn in &victims {
+            conn.kill(reason);
+        }
+        victims.len()
+    }
+
+    /// Close the connections that have been idle or open for too long.
+    pub fn expire(&self, timeouts: &Timeouts) {
+        let active: Vec<_> = {
+            let state = self.state.lock().unwrap();
+            state.active.values().filter_map(Weak::upgrade).collect()
+        };
+        for conn in active {
+            if let Some(max) = timeouts.lifetime {
+                if conn.started.elapsed() > max {
+                    conn.kill(&format!("connection open for more than {}s", max.as_secs()));
+                    continue;
+                }
+            }
+            if let Some(max) = timeouts.idle {
+                if conn.idle() > max {
+                    conn.kill(&format!("connection idle for more than {}s", max.as_secs()));
+                }
+            }
+        }
+    }
+
+    /// Call [`Connections::expire`] regularly until shutdown begins.
+    pub fn enforce(&self, timeouts: Timeouts) {
+        let shortest = timeouts.idle.into_iter().chain(timeouts.lifetime).min();
+        let Some(shortest) = shortest else {
+            return;
+        };
+        let interval = (shortest / 10).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        while !self.shutting_down() {
+            self.expire(&timeouts);
+            thread::sleep(interval);
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.state.lock().unwrap().totals
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timeouts {
+    /// Close connections without traffic in either direction for this long
+    pub idle: Option<Duration>,
+    /// Close connections that have been open this long
+    pub lifetime: Option<Duration>,
+}
+
+pub struct Connection {
+    pub id: u64,
+    owner: Arc<Connections>,
+    closers: Vec<Closer>,
+    _ticket: Ticket,
+    kill_reason: Mutex<Option<String>>,
+    started: Instant,
+    /// Nanoseconds since `started`
+    last_activity: AtomicU64,
+    from_client: AtomicU64,
+    from_server: AtomicU64,
+}
+
+impl Connection {
+    /// Count bytes received from the given side.
+    pub fn count(&self, side: Side, n: usize) {
+        let counter = match side {
+            Side::Client => &self.from_client,
+            Side::Server => &self.from_server,
+        };
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        let now = self.started.elapsed().as_nanos() as u64;
+        self.last_activity.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        let last = Duration::from_nanos(self.last_activity.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+
+    /// Close both sides of the connection. The threads pumping the data
+    /// will notice and can retrieve the reason with [`Connection::killed`].
+    pub fn kill(&self, reason: &str) {
+        if self.set_kill_reason(reason) {
+            for closer in &self.closers {
+                closer.close();
+            }
+        }
+    }
+
+    /// Like [`Connection::kill`] but abort TCP connections with a RST.
+    pub fn reset(&self, reason: &str) {
+        if self.set_kill_reason(reason) {
+            for closer in &self.closers {
+                closer.reset();
+            }
+        }
+    }
+
+    /// Returns false if the connection has already been killed.
+    fn set_kill_reason(&self, reason: &str) -> bool {
+        {
+            let mut kill_reason = self.kill_reason.lock().unwrap();
+            if kill_reason.is_some() {
+                return false;
+            }
+            *kill_reason = Some(reason.to_string());
+        }
+        self.owner.state.lock().unwrap().totals.killed += 1;
+        true
+    }
+
+    pub fn killed(&self) -> Option<String> {
+        self.kill_reason.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut state = self.owner.state.lock().unwrap();
+        state.active.remove(&self.id);
+        state.totals.from_client += *self.from_client.get_mut();
+        state.totals.from_server += *self.from_server.get_mut();
+        self.owner.changed.notify_all();
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut state = self.owner.state.lock().unwrap();
+        state.admitted -= 1;
+        state.gates[self.gate] -= 1;
+        self.owner.changed.notify_all();
+    }
+}
@@ -0,0 +1,154 @@
+//! Stepping through a session one message at a time, asking on the terminal
+//! what to do with each of them.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::formatter::Side;
+
+const HELP: &str = "\
+    f, Enter    forward the message
+    d           drop it
+    e [TEXT]    replace it with TEXT, or edit it in $EDITOR
+    s TEXT      send TEXT to the server first
+    c TEXT      send TEXT to the client first
+    g           stop stepping, forward everything from now on
+    TEXT may contain the escapes \\n, \\t and \\\\";
+
+/// What the user wants done with a message.
+#[derive(Debug)]
+pub enum Command {
+    Forward,
+    Drop,
+    /// Replace the message with this
+    Edit(Vec<u8>),
+    /// Send this message to that side, then ask again
+    Inject(Side, Vec<u8>),
+}
+
+#[derive(Debug)]
+pub struct Debugger {
+    /// Only one message is discussed at a time
+    terminal: Mutex<()>,
+    stepping: AtomicBool,
+    edits: AtomicUsize,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger {
+            terminal: Mutex::new(()),
+            stepping: AtomicBool::new(true),
+            edits: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Debugger {
+    pub fn stepping(&self) -> bool {
+        self.stepping.load(Ordering::Relaxed)
+    }
+
+    /// Ask what to do with a message from `side`. When the user stops
+    /// stepping or the terminal is closed, the answer is always
+    /// [`Command::Forward`].
+    pub fn ask(&self, side: Side, message: &[u8]) -> Command {
+        let _terminal = self.terminal.lock().unwrap();
+        let stdin = io::stdin();
+        while self.stepping() {
+            eprint!("{side} message held, command (? for help): ");
+            let _ = io::stderr().flush();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                eprintln!();
+                self.stop();
+                break;
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            let (command, text) = line.split_once(' ').unwrap_or((line, ""));
+            match command {
+                "" | "f" => return Command::Forward,
+                "d" => return Command::Drop,
+                "e" if text.is_empty() => match self.edit(message) {
+                    Ok(edited) => return Command::Edit(edited),
+                    Err(e) => eprintln!("could not edit the message: {e}"),
+                },
+                "e" => return Command::Edit(unescape(text)),
+                "s" => return Command::Inject(Side::Server, unescape(text)),
+                "c" => return Command::Inject(Side::Client, unescape(text)),
+                "g" => self.stop(),
+                _ => eprintln!("{HELP}"),
+            }
+        }
+        Command::Forward
+    }
+
+    fn stop(&self) {
+        self.stepping.store(false, Ordering::Relaxed);
+    }
+
+    fn edit(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let n = self.edits.fetch_add(1, Ordering::Relaxed);
+        let dir = private_dir()?;
+        let path = dir.join(format!("message-{n}.txt"));
+        let result = edit_file(&path, message);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_dir(&dir);
+        result
+    }
+}
+
+fn edit_file(path: &Path, message: &[u8]) -> io::Result<Vec<u8>> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(message)?;
+    let editor = env::var_os("VISUAL")
+        .or_else(|| env::var_os("EDITOR"))
+        .unwrap_or("vi".into());
+    let status = process::Command::new(editor).arg(path).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("editor exited with {status}")));
+    }
+    fs::read(path)
+}
+
+/// A new directory only we can use, so nobody can put a symlink where the
+/// editor will write.
+fn private_dir() -> io::Result<PathBuf> {
+    loop {
+        let name = format!("monetproxy-{}-{:08x}", process::id(), fastrand::u32(..));
+        let dir = env::temp_dir().join(name);
+        match fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            other => return other.map(|()| dir),
+        }
+    }
+}
+
+fn unescape(text: &str) -> Vec<u8> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => unescaped.extend(['\\', other]),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped.into_bytes()
+}
@@ -0,0 +1,720 @@
+//! Alternative to the thread-per-direction design in [`crate::proxy`].
+//! A single thread waits for readiness events on all listeners and
+//! connections and moves the data along without blocking.
+//! The observers see exactly the same calls as with the threads.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::TcpStream;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token};
+
+use crate::capture::{Event, Recorder, Recording};
+use crate::connections::{Connection, Connections, Gate, Ticket, WhenFull};
+use crate::formatter::{Formatter, Side};
+use crate::network::{Address, Incoming, Listener, Outgoing};
+use crate::proxy::{self, Destination, Observer, BLOCKSIZE};
+
+pub fn spawn_event_loop<O, I, F>(
+    listeners: Vec<(Listener, Gate)>,
+    forward_to: Destination,
+    connections: Arc<Connections>,
+    formatter: Arc<Mutex<O>>,
+    recorder: Option<Arc<Recorder>>,
+    make_inspector: F,
+) -> io::Result<JoinHandle<()>>
+where
+    O: Formatter + Send + 'static,
+    I: Observer + Send + 'static,
+    F: FnMut(Side, Arc<Mutex<O>>) -> I + Send + 'static,
+{
+    if forward_to.tls.is_some() {
+        let kind = io::ErrorKind::Unsupported;
+        return Err(io::Error::new(
+            kind,
+            "the event loop cannot connect using TLS",
+        ));
+    }
+
+    let mut event_loop = EventLoop {
+        poll: Poll::new()?,
+        next_token: 0,
+        listeners: HashMap::new(),
+        full: vec![],
+        sockets: HashMap::new(),
+        conns: HashMap::new(),
+        next_conn: 0,
+        forward_to,
+        connections,
+        formatter,
+        recorder,
+        make_inspector,
+        scratch: vec![0; BLOCKSIZE],
+    };
+    for (listener, gate) in listeners {
+        listener.set_nonblocking()?;
+        let token = event_loop.register(listener.as_raw_fd(), Interest::READABLE)?;
+        event_loop.listeners.insert(token, (listener, gate));
+    }
+
+    Ok(proxy::spawn_worker("event-loop", move || event_loop.run()))
+}
+
+struct EventLoop<O, I, F> {
+    poll: Poll,
+    next_token: usize,
+    listeners: HashMap<Token, (Listener, Gate)>,
+    /// Queueing listeners that stopped accepting because they are full
+    full: Vec<Token>,
+    /// Which connection each registered socket belongs to
+    sockets: HashMap<Token, u64>,
+    conns: HashMap<u64, Conn<Recording<I>>>,
+    next_conn: u64,
+    forward_to: Destination,
+    connections: Arc<Connections>,
+    formatter: Arc<Mutex<O>>,
+    recorder: Option<Arc<Recorder>>,
+    make_inspector: F,
+    /// All reads go here first, only data that cannot be written
+    /// immediately is copied to the connection
+    scratch: Vec<u8>,
+}
+
+struct Conn<I> {
+    listen_addr: Address,
+    client_addr: Address,
+    client_in: Incoming,
+    client_out: Outgoing,
+    client_fd: RawFd,
+    client_token: Token,
+    server_token: Token,
+    server: Server<I>,
+    /// Until the connection to the server has been established
+    ticket: Option<Ticket>,
+}
+
+enum Server<I> {
+    /// Waiting for the TCP connection to be established
+    Connecting(TcpStream),
+    Open(Box<Session<I>>),
+}
+
+impl<I> Server<I> {
+    fn fd(&self) -> RawFd {
+        match self {
+            Server::Connecting(stream) => stream.as_raw_fd(),
+            Server::Open(session) => session.server_fd,
+        }
+    }
+}
+
+struct Session<I> {
+    server_in: Incoming,
+    server_out: Outgoing,
+    server_fd: RawFd,
+    handle: Arc<Connection>,
+    upstream: Direction<I>,
+    downstream: Direction<I>,
+}
+
+enum Connected {
+    Ready(Incoming, Outgoing, Address),
+    InProgress(TcpStream),
+}
+
+impl<O, I, F> EventLoop<O, I, F>
+where
+    O: Formatter,
+    I: Observer,
+    F: FnMut(Side, Arc<Mutex<O>>) -> I,
+{
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        while !self.listeners.is_empty() || !self.conns.is_empty() {
+            match self.poll.poll(&mut events, None) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                other => other?,
+            }
+            for event in events.iter() {
+                let token = event.token();
+                if self.listeners.contains_key(&token) {
+                    self.accept(token);
+                } else if let Some(&id) = self.sockets.get(&token) {
+                    self.drive(id, token);
+                }
+            }
+            // Connections may have closed, making room for queued clients.
+            // Their listeners won't get another event so try them now.
+            for token in mem::take(&mut self.full) {
+                if self.listeners.contains_key(&token) {
+                    self.accept(token);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn register(&mut self, fd: RawFd, interest: Interest) -> io::Result<Token> {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.poll
+            .registry()
+            .register(&mut SourceFd(&fd), token, interest)?;
+        Ok(token)
+    }
+
+    fn deregister(&mut self, fd: RawFd) {
+        let _ = self.poll.registry().deregister(&mut SourceFd(&fd));
+    }
+
+    /// Show a message about the proxy itself. Failing to do so must not
+    /// stop the event loop, so fall back to stderr.
+    fn report(&self, message: &str) {
+        if let Err(e) = self.formatter.lock().unwrap().proxy_message(message) {
+            eprintln!("{message}");
+            eprintln!("(could not show this message: {e})");
+        }
+    }
+
+    fn accept(&mut self, token: Token) {
+        // The listeners are woken up by their Stopper when shutting down, but
+        // a non-blocking accept on a Unix socket doesn't report that as an error.
+        if self.connections.shutting_down() {
+            let (listener, _) = self.listeners.remove(&token).unwrap();
+            self.deregister(listener.as_raw_fd());
+            if self.listeners.is_empty() {
+                self.abandon_pending();
+            }
+            return;
+        }
+        loop {
+            let (listener, gate) = self.listeners.get_mut(&token).unwrap();
+            let local = listener.local.clone();
+            let queued = match gate.when_full {
+                WhenFull::Queue => match self.connections.admit(gate, false) {
+                    Ok(ticket) => Some(ticket),
+                    Err(_) => {
+                        // Leave the clients in the listen queue
+                        self.full.push(token);
+                        return;
+                    }
+                },
+                WhenFull::Refuse => None,
+            };
+            let (client_in, mut client_out, client_addr) = match (listener.accepter)() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    eprintln!("Accepting on {local} failed: {e}");
+                    return;
+                }
+            };
+            let ticket = match queued.map_or_else(|| self.connections.admit(gate, false), Ok) {
+                Ok(ticket) => ticket,
+                Err(reason) => {
+                    let _ = proxy::refuse(&mut client_out, &reason);
+                    self.report(&format!("refused connection on {local}: {reason}"));
+                    continue;
+                }
+            };
+            let client = client_addr.to_string();
+            if let Err(e) = self.start(local.clone(), client_addr, client_in, client_out, ticket) {
+                self.report(&format!(
+                    "could not start connection from {client} on {local}: {e}"
+                ));
+            }
+        }
+    }
+
+    fn start(
+        &mut self,
+        listen_addr: Address,
+        client_addr: Address,
+        client_in: Incoming,
+        client_out: Outgoing,
+        ticket: Ticket,
+    ) -> io::Result<()> {
+        client_in.set_nonblocking()?;
+
+        let connected = match self.connect() {
+            Ok(c) => c,
+            Err(e) => {
+                let msg = format!("could not connect to {dest}: {e}", dest = self.forward_to);
+                return self.formatter.lock().unwrap().proxy_message(&msg);
+            }
+        };
+
+        let (server, ticket) = match connected {
+            Connected::InProgress(stream) => (Server::Connecting(stream), Some(ticket)),
+            Connected::Ready(server_in, server_out, server_addr) => {
+                server_in.set_nonblocking()?;
+                let session = self.open(
+                    &listen_addr,
+                    &client_addr,
+                    &client_in,
+                    server_in,
+                    server_out,
+                    server_addr,
+                    ticket,
+                )?;
+                (Server::Open(session), None)
+            }
+        };
+
+        let client_fd = client_in.as_raw_fd();
+        let both = Interest::READABLE | Interest::WRITABLE;
+        let client_token = self.register(client_fd, both)?;
+        let server_token = match self.register(server.fd(), both) {
+            Ok(token) => token,
+            Err(e) => {
+                self.deregister(client_fd);
+                return Err(e);
+            }
+        };
+        let id = self.next_conn;
+        self.next_conn += 1;
+        self.sockets.insert(client_token, id);
+        self.sockets.insert(server_token, id);
+
+        let conn = Conn {
+            listen_addr,
+            client_addr,
+            client_in,
+            client_out,
+            client_fd,
+            client_token,
+            server_token,
+            server,
+            ticket,
+        };
+        self.conns.insert(id, conn);
+        self.drive(id, client_token);
+        Ok(())
+    }
+
+    fn connect(&self) -> io::Result<Connected> {
+        let addr = &self.forward_to.addr;
+        if let Some(unix) = addr.to_unix() {
+            if let Ok((r, w, peer)) = proxy::connect_unix(unix) {
+                return Ok(Connected::Ready(r, w, peer));
+            }
+        }
+        if let Some(Address::Inet(a)) = addr.to_inet() {
+            return Ok(Connected::InProgress(a.start_connect()?));
+        }
+        let kind = io::ErrorKind::ConnectionRefused;
+        Err(io::Error::new(kind, format!("can't connect to {addr}")))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn open(
+        &mut self,
+        listen_addr: &Address,
+        client_addr: &Address,
+        client_in: &Incoming,
+        server_in: Incoming,
+        server_out: Outgoing,
+        server_addr: Address,
+        ticket: Ticket,
+    ) -> io::Result<Box<Session<Recording<I>>>> {
+        self.formatter
+            .lock()
+            .unwrap()
+            .connected(listen_addr, &server_addr)?;
+
+        let closers = vec![client_in.closer()?, server_in.closer()?];
+        let handle = self.connections.register(closers, ticket);
+
+        let from_unix = matches!(client_in, Incoming::Unix(_));
+        let to_unix = matches!(server_out, Outgoing::Unix(_));
+        if let Some(recorder) = &self.recorder {
+            let (client, server) = (client_addr.to_string(), server_addr.to_string());
+            recorder.record(handle.id, Event::Connected { client, server })?;
+        }
+        let observe =
+            |side, inspector| Recording::new(inspector, self.recorder.clone(), handle.id, side);
+        let own =
+            self.formatter
+                .lock()
+                .unwrap()
+                .for_connection(handle.id, client_addr, &server_addr);
+        let formatter = match own {
+            Ok(Some(mut own)) => {
+                own.connected(listen_addr, &server_addr)?;
+                Arc::new(Mutex::new(own))
+            }
+            Ok(None) => Arc::clone(&self.formatter),
+            Err(e) => {
+                let message = format!(
+                    "could not write connection {} to a file of its own, it is shown here: {e}",
+                    handle.id
+                );
+                self.formatter.lock().unwrap().proxy_message(&message)?;
+                Arc::clone(&self.formatter)
+            }
+        };
+        let inspect_client = (self.make_inspector)(Side::Client, Arc::clone(&formatter));
+        let inspect_server = (self.make_inspector)(Side::Server, Arc::clone(&formatter));
+        let mut upstream = Direction::new(Side::Client, observe(Side::Client, inspect_client));
+        let downstream = Direction::new(Side::Server, observe(Side::Server, inspect_server));
+        let remark = proxy::unix0_remark(from_unix, to_unix);
+        if from_unix {
+            // Reported when the '0' arrives
+            upstream.expect_unix0 = true;
+            upstream.unix0_remark = remark;
+        } else if let Some((data, message)) = remark {
+            upstream.observer.on_unix0(data, message)?;
+        }
+        if to_unix {
+            upstream.pending.push(b'0');
+        }
+
+        Ok(Box::new(Session {
+            server_fd: server_in.as_raw_fd(),
+            server_in,
+            server_out,
+            handle,
+            upstream,
+            downstream,
+        }))
+    }
+
+    /// Make as much progress as possible on the connection after an event on one of its sockets.
+    /// Errors only close this connection.
+    fn drive(&mut self, id: u64, token: Token) {
+        let Some(mut conn) = self.conns.remove(&id) else {
+            return;
+        };
+        match self.advance(&mut conn, token) {
+            Ok(true) => {
+                self.conns.insert(id, conn);
+            }
+            Ok(false) => self.close(conn, None),
+            Err(e) => self.close(conn, Some(e)),
+        }
+    }
+
+    /// Returns whether the connection is still open.
+    fn advance(&mut self, conn: &mut Conn<Recording<I>>, token: Token) -> io::Result<bool> {
+        if let Server::Connecting(stream) = &conn.server {
+            if token != conn.server_token {
+                // Client will have to wait
+                return Ok(true);
+            }
+            let outcome = match stream.take_error() {
+                Ok(Some(e)) | Err(e) => Err(e),
+                Ok(None) => stream.peer_addr(),
+            };
+            match outcome {
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => return Ok(true),
+                Err(e) => {
+                    self.report(&format!(
+                        "could not connect to {dest}: {e}",
+                        dest = self.forward_to
+                    ));
+                    return Ok(false);
+                }
+                Ok(peer) => {
+                    let server_in = Incoming::Inet(stream.try_clone()?);
+                    let server_out = Outgoing::Inet(stream.try_clone()?);
+                    let server_addr = Address::Inet(peer.into());
+                    let ticket = conn.ticket.take().unwrap();
+                    let session = self.open(
+                        &conn.listen_addr,
+                        &conn.client_addr,
+                        &conn.client_in,
+                        server_in,
+                        server_out,
+                        server_addr,
+                        ticket,
+                    )?;
+                    // The session has its own copies of the socket, the
+                    // original is closed below
+                    self.deregister(stream.as_raw_fd());
+                    let both = Interest::READABLE | Interest::WRITABLE;
+                    let fd = session.server_fd;
+                    self.poll
+                        .registry()
+                        .register(&mut SourceFd(&fd), conn.server_token, both)?;
+                    conn.server = Server::Open(session);
+                }
+            }
+        }
+
+        let Server::Open(session) = &mut conn.server else {
+            unreachable!()
+        };
+        let scratch = &mut self.scratch;
+        let outcome = session
+            .upstream
+            .progress(
+                &mut conn.client_in,
+                &mut session.server_out,
+                &session.handle,
+                scratch,
+            )
+            .and_then(|()| {
+                session.downstream.progress(
+                    &mut session.server_in,
+                    &mut conn.client_out,
+                    &session.handle,
+                    scratch,
+                )
+            });
+
+        outcome?;
+        Ok(!(session.upstream.done && session.downstream.done))
+    }
+
+    fn close(&mut self, conn: Conn<Recording<I>>, err: Option<io::Error>) {
+        if let Some(e) = err {
+            self.report(&format!(
+                "connection from {client} on {local} failed: [{k:?}] {e:#}",
+                client = conn.client_addr,
+                local = conn.listen_addr,
+                k = e.kind()
+            ));
+        }
+        self.deregister(conn.client_fd);
+        self.deregister(conn.server.fd());
+        self.sockets.remove(&conn.client_token);
+        self.sockets.remove(&conn.server_token);
+    }
+
+    /// No more listeners so nobody is going to wait for connections that
+    /// haven't been established yet.
+    fn abandon_pending(&mut self) {
+        let pending: Vec<u64> = self
+            .conns
+            .iter()
+            .filter(|(_, c)| matches!(c.server, Server::Connecting(_)))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in pending {
+            let conn = self.conns.remove(&id).unwrap();
+            self.close(conn, None);
+        }
+    }
+}
+
+/// One direction of a connection, the equivalent of `pump` in [`crate::proxy`].
+struct Direction<I> {
+    side: Side,
+    observer: I,
+    /// Data that has been read but not yet written
+    pending: Vec<u8>,
+    expect_unix0: bool,
+    unix0_remark: Option<(&'static [u8], Option<&'static str>)>,
+    eof: bool,
+    done: bool,
+}
+
+impl<I: Observer> Direction<I> {
+    fn new(side: Side, observer: I) -> Self {
+        Direction {
+            side,
+            observer,
+            pending: vec![],
+            expect_unix0: false,
+            unix0_remark: None,
+            eof: false,
+            done: false,
+        }
+    }
+
+    fn progress(
+        &mut self,
+        r: &mut Incoming,
+        w: &mut Outgoing,
+        conn: &Connection,
+        scratch: &mut [u8],
+    ) -> io::Result<()> {
+        while !self.done {
+            if !self.pending.is_empty() {
+                match w.write(&self.pending) {
+                    Ok(n) => {
+                        self.pending.drain(..n);
+                        continue;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) => return self.write_failed(r, conn, e),
+                }
+            }
+
+            if self.eof {
+                let _ = w.shutdown();
+                self.done = true;
+                return Ok(());
+            }
+
+            let nread = match r.read(scratch) {
+                Ok(0) => {
+                    self.eof = true;
+                    match proxy::kill_error(conn) {
+                        Some(e) => self.observer.on_error(false, &e)?,
+                        None => self.observer.on_close()?,
+                    }
+                    continue;
+                }
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.observer
+                        .on_error(false, &proxy::explain_error(conn, e))?;
+                    let _ = w.shutdown();
+                    self.done = true;
+                    return Ok(());
+                }
+            };
+
+            let mut data = &scratch[..nread];
+            if self.expect_unix0 {
+                self.expect_unix0 = false;
+                proxy::check_unix0(data[0])?;
+                if let Some((d, message)) = self.unix0_remark {
+                    self.observer.on_unix0(d, message)?;
+                }
+                data = &data[1..];
+            }
+            if data.is_empty() {
+                continue;
+            }
+
+            conn.count(self.side, data.len());
+            if self.observer.wants_data() {
+                self.observer.on_data(data)?;
+            } else {
+                self.observer.on_forwarded(data.len())?;
+            }
+
+            match w.write(data) {
+                Ok(n) => self.pending.extend_from_slice(&data[n..]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.pending.extend_from_slice(data)
+                }
+                Err(e) => return self.write_failed(r, conn, e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_failed(
+        &mut self,
+        r: &mut Incoming,
+        conn: &Connection,
+        err: io::Error,
+    ) -> io::Result<()> {
+        self.observer
+            .on_error(true, &proxy::explain_error(conn, err))?;
+        let _ = r.shutdown();
+        self.pending = vec![];
+        self.done = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use crate::formatter::TextFormatter;
+    use crate::network::UnixOptions;
+    use crate::observers::BlockObserver;
+    use crate::tamper::frame;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn read_message(r: &mut impl Read) -> Vec<u8> {
+        let mut message = vec![];
+        loop {
+            let mut header = [0; 2];
+            r.read_exact(&mut header).unwrap();
+            let header = u16::from_le_bytes(header);
+            let start = message.len();
+            message.resize(start + (header >> 1) as usize, 0);
+            r.read_exact(&mut message[start..]).unwrap();
+            if header & 1 != 0 {
+                return message;
+            }
+        }
+    }
+
+    #[test]
+    fn test_framed_exchange() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let echo = thread::spawn(move || {
+            let (mut conn, _) = server.accept().unwrap();
+            let query = read_message(&mut conn);
+            conn.write_all(&frame(&[b"&ok ", &query[..]].concat()))
+                .unwrap();
+        });
+
+        let listener = Address::parse("tcp4:127.0.0.1:0")
+            .unwrap()
+            .listen(&UnixOptions::default())
+            .unwrap();
+        let Address::Inet(local) = &listener.local else {
+            unreachable!()
+        };
+        let port = local.port;
+        let stopper = listener.stopper.clone();
+        let connections = Connections::new();
+        let gate = connections.gate(None, WhenFull::Refuse);
+        let output = Output::default();
+        let formatter = Arc::new(Mutex::new(TextFormatter::new(output.clone())));
+        let forward_to = Destination {
+            addr: Address::Inet(server_addr.into()),
+            tls: None,
+        };
+        let event_loop = spawn_event_loop(
+            vec![(listener, gate)],
+            forward_to,
+            Arc::clone(&connections),
+            Arc::clone(&formatter),
+            None,
+            BlockObserver::new,
+        )
+        .unwrap();
+
+        // Two blocks
+        let query = [b"s".as_slice(), &[b'x'; BLOCKSIZE]].concat();
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(&frame(&query)).unwrap();
+        let reply = read_message(&mut client);
+        assert_eq!(reply, [b"&ok ", &query[..]].concat());
+        drop(client);
+        echo.join().unwrap();
+
+        connections.begin_shutdown();
+        stopper.stop();
+        event_loop.join().unwrap();
+        formatter.lock().unwrap().flush().unwrap();
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("CLIENT text, 8190 bytes, no trailing newline, does not end"));
+        assert!(output.contains("CLIENT text, 1 bytes, no trailing newline, ends the message"));
+        assert!(output.contains("SERVER text, 5 bytes, no trailing newline, ends the message"));
+        assert!(output.contains("CLIENT closed its side of the connection"));
+        assert_eq!(connections.active(), 0);
+    }
+}
@@ -0,0 +1,118 @@
+//! Deciding which reassembled messages are worth printing.
+
+use regex::Regex;
+
+use crate::formatter::Side;
+use crate::tamper;
+
+/// The kinds of message that can be selected, see [`kind`].
+pub const KINDS: &[&str] = &[
+    "sql",
+    "command",
+    "query",
+    "update",
+    "schema",
+    "transaction",
+    "prepare",
+    "block",
+    "error",
+    "prompt",
+    "redirect",
+    "other",
+];
+
+/// A message is printed if it passes all conditions that are set.
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Only messages from this side
+    pub side: Option<Side>,
+    /// Only messages of one of these kinds
+    pub kinds: Vec<&'static str>,
+    /// Only messages matching one of these
+    pub matching: Vec<Regex>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.side.is_none() && self.kinds.is_empty() && self.matching.is_empty()
+    }
+
+    pub fn accepts(&self, side: Side, message: &[u8]) -> bool {
+        if self.side.is_some_and(|s| s != side) {
+            return false;
+        }
+        if !self.kinds.is_empty() && !self.kinds.contains(&kind(side, message)) {
+            return false;
+        }
+        if !self.matching.is_empty() {
+            let text = match side {
+                Side::Client => tamper::query_text(message),
+                Side::Server => String::from_utf8_lossy(message),
+            };
+            if !self.matching.iter().any(|r| r.is_match(&text)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Classify a message by its first bytes. A server message with an error
+/// anywhere in it is an error, even if it starts with a result.
+pub fn kind(side: Side, message: &[u8]) -> &'static str {
+    if side == Side::Client {
+        return match message.first() {
+            Some(b's') => "sql",
+            Some(b'X') => "command",
+            _ => "other",
+        };
+    }
+    if message.is_empty() {
+        return "prompt";
+    }
+    if message
+        .split(|&b| b == b'\n')
+        .any(|line| line.starts_with(b"!"))
+    {
+        return "error";
+    }
+    match message {
+        [b'&', b'1', ..] => "query",
+        [b'&', b'2', ..] => "update",
+        [b'&', b'3', ..] => "schema",
+        [b'&', b'4', ..] => "transaction",
+        [b'&', b'5', ..] => "prepare",
+        [b'&', b'6', ..] => "block",
+        [b'^', ..] => "redirect",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind() {
+        assert_eq!(kind(Side::Client, b"sselect 1;"), "sql");
+        assert_eq!(kind(Side::Client, b"Xreply_size 100"), "command");
+        assert_eq!(kind(Side::Client, b"LOGIN"), "other");
+        assert_eq!(kind(Side::Server, b""), "prompt");
+        assert_eq!(kind(Side::Server, b"&1 0 1 1 1\n[ 1\t]\n"), "query");
+        assert_eq!(kind(Side::Server, b"&2 1 -1\n"), "update");
+        assert_eq!(kind(Side::Server, b"&3\n"), "schema");
+        assert_eq!(kind(Side::Server, b"&4 f\n"), "transaction");
+        assert_eq!(kind(Side::Server, b"&5 0 1 1 1\n"), "prepare");
+        assert_eq!(kind(Side::Server, b"&6 0 1 1 1\n"), "block");
+        assert_eq!(
+            kind(Side::Server, b"^mapi:merovingian://proxy\n"),
+            "redirect"
+        );
+        assert_eq!(kind(Side::Server, b"!42000!syntax error\n"), "error");
+        assert_eq!(
+            kind(Side::Server, b"&2 1 -1\n!40000!COMMIT: failed\n"),
+            "error"
+        );
+        assert_eq!(kind(Side::Server, b"=OK\n"), "other");
+    }
+}
@@ -0,0 +1,179 @@
+//! Decide which SQL statements clients are allowed to send.
+
+use regex::Regex;
+
+use crate::sql::{self, Statement};
+
+/// Statements that do not modify data or schema.
+const READ_ONLY: &[&str] = &[
+    "SELECT",
+    "VALUES",
+    "WITH",
+    "SET",
+    "START",
+    "COMMIT",
+    "ROLLBACK",
+    "SAVEPOINT",
+    "RELEASE",
+    "EXEC",
+    "EXECUTE",
+    "DEALLOCATE",
+];
+
+/// These are followed by another statement that decides the verdict.
+const WRAPPERS: &[&str] = &["EXPLAIN", "PLAN", "TRACE", "DEBUG", "PREPARE"];
+
+/// A WITH can be followed by any of these.
+const MODIFYING: &[&str] = &["INSERT", "UPDATE", "DELETE", "MERGE"];
+
+/// Commands sent as X messages that only affect how results are delivered.
+const HARMLESS_COMMANDS: &[&str] = &[
+    "auto_commit",
+    "reply_size",
+    "sizeheader",
+    "export",
+    "close",
+    "release",
+    "clientinfo",
+];
+
+/// Blocks statements matching `pattern`, unless they also match `unless`.
+#[derive(Debug)]
+pub struct DenyRule {
+    pub pattern: Regex,
+    pub unless: Option<Regex>,
+}
+
+#[derive(Debug, Default)]
+pub struct Firewall {
+    /// If set, only statements of these kinds are let through. Upper case.
+    pub allow: Option<Vec<String>>,
+    pub deny: Vec<DenyRule>,
+    pub read_only: bool,
+}
+
+impl Firewall {
+    /// Check every statement in the SQL. Returns why it must be blocked,
+    /// if it must be blocked.
+    pub fn check(&self, sql: &str) -> Result<(), String> {
+        for statement in sql::statements(sql) {
+            if self.read_only {
+                check_read_only(&statement)?;
+            }
+            if let Some(allow) = &self.allow {
+                if !allow.contains(&statement.kind) {
+                    return Err(format!("{} statements are not allowed", statement.kind));
+                }
+            }
+            for rule in &self.deny {
+                let exempt = rule
+                    .unless
+                    .as_ref()
+                    .is_some_and(|r| r.is_match(&statement.text));
+                if rule.pattern.is_match(&statement.text) && !exempt {
+                    return Err(format!("statement matches {}", rule.pattern));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check an X command, the part after the 'X'.
+    pub fn check_command(&self, command: &str) -> Result<(), String> {
+        let name = command.split_whitespace().next().unwrap_or_default();
+        if self.read_only && !HARMLESS_COMMANDS.contains(&name) {
+            return Err(format!("read-only mode does not allow command {name:?}"));
+        }
+        Ok(())
+    }
+}
+
+/// PREPARE is judged by the statement it prepares, so an EXEC can only
+/// ever run statements that have passed.
+fn check_read_only(statement: &Statement) -> Result<(), String> {
+    let words = &statement.words;
+    let start = words
+        .iter()
+        .position(|w| !WRAPPERS.contains(&w.as_str()))
+        .unwrap_or(words.len());
+    let kind = words.get(start).map_or("", String::as_str);
+    if !READ_ONLY.contains(&kind) {
+        return Err(format!("read-only mode does not allow {kind} statements"));
+    }
+    if kind == "WITH" {
+        if let Some(word) = words[start..]
+            .iter()
+            .find(|w| MODIFYING.contains(&w.as_str()))
+        {
+            return Err(format!(
+                "read-only mode does not allow WITH ... {word} statements"
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_only() -> Firewall {
+        Firewall {
+            read_only: true,
+            ..Firewall::default()
+        }
+    }
+
+    #[test]
+    fn test_read_only() {
+        let firewall = read_only();
+        assert!(firewall.check("select * from t where x = 'delete'").is_ok());
+        assert!(firewall.check("explain select 1; commit").is_ok());
+        assert!(firewall
+            .check("with x as (select 1) select * from x")
+            .is_ok());
+        assert!(firewall
+            .check("prepare select * from t where x = ?")
+            .is_ok());
+        assert!(firewall.check("delete from t").is_err());
+        assert!(firewall.check("select 1; delete from t").is_err());
+        assert!(firewall
+            .check("select 1 -- comment\n; drop table t")
+            .is_err());
+        assert!(firewall.check(r"select r'\'; delete from t").is_err());
+        assert!(firewall
+            .check("with x as (select 1) delete from t where y in (select * from x)")
+            .is_err());
+        assert!(firewall.check("prepare delete from t").is_err());
+        assert!(firewall
+            .check("explain prepare insert into t values (1)")
+            .is_err());
+    }
+
+    #[test]
+    fn test_commands() {
+        let firewall = read_only();
+        assert!(firewall.check_command("reply_size 100").is_ok());
+        assert!(firewall.check_command("sizeheader 1").is_ok());
+        assert!(firewall.check_command("quit").is_err());
+        assert!(Firewall::default().check_command("quit").is_ok());
+    }
+
+    #[test]
+    fn test_allow_and_deny() {
+        let firewall = Firewall {
+            allow: Some(vec!["SELECT".to_string()]),
+            deny: vec![DenyRule {
+                pattern: Regex::new("(?i)secret").unwrap(),
+                unless: Some(Regex::new("(?i)count").unwrap()),
+            }],
+            read_only: false,
+        };
+        assert!(firewall.check("select * from t").is_ok());
+        assert!(firewall.check("select count(*) from secret").is_ok());
+        assert!(firewall.check("select * from secret").is_err());
+        assert!(firewall
+            .check("select 1; insert into t values (1)")
+            .is_err());
+    }
+}
@@ -1,11 +1,21 @@
 use box_drawing::light as boxchars;
 use std::{
+    borrow::Cow,
     fmt,
+    fs::{self, File, OpenOptions},
     io::{self, BufWriter, Write},
+    mem,
+    ops::Range,
+    path::{Path, PathBuf},
     str::from_utf8,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-#[derive(Debug, Clone, Copy)]
+use crate::output::{Event, Queue, WhenBehind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Side {
     Client,
     Server,
@@ -23,17 +33,140 @@ impl fmt::Display for Side {
 
 pub trait Formatter: io::Write {
     fn connected(&mut self, local: &dyn fmt::Display, remote: &dyn fmt::Display) -> io::Result<()>;
+    fn proxy_message(&mut self, message: &str) -> io::Result<()>;
     fn message(&mut self, side: Side, message: &str) -> io::Result<()>;
     fn start_block(&mut self, side: Side, message: &str) -> io::Result<()>;
     fn end_block(&mut self) -> io::Result<()>;
-    fn force_binary(&self) -> bool;
+
+    /// How observers should prepare the messages they pass to
+    /// [`Formatter::print`]. Preparing happens without holding the lock
+    /// on the formatter.
+    fn preparer(&self) -> Preparer {
+        Preparer::default()
+    }
+
+    fn print(&mut self, message: Prepared) -> io::Result<()>
+    where
+        Self: Sized,
+    {
+        let message = message.finish(&self.preparer())?;
+        print_shown(self, &message)
+    }
+
+    /// A formatter of its own for the traffic of one connection, if
+    /// connections are written to separate files.
+    fn for_connection(
+        &mut self,
+        _id: u64,
+        _client: &dyn fmt::Display,
+        _server: &dyn fmt::Display,
+    ) -> io::Result<Option<Self>>
+    where
+        Self: Sized,
+    {
+        Ok(None)
+    }
+}
+
+/// Limits on how much of a message is shown. Text is shown as its first
+/// `head` lines and last `tail` lines, each part at most `bytes` long.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub head: Option<usize>,
+    pub tail: Option<usize>,
+    pub bytes: Option<usize>,
+    /// Only show the first so many bytes of binary messages
+    pub binary: Option<usize>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    /// How much of a binary message to show, --max-bytes stands in for
+    /// --max-binary when that is not given.
+    fn binary_max(&self) -> Option<usize> {
+        self.binary.or(self.bytes)
+    }
+
+    /// Which part of the text to leave out, if any.
+    fn omit(&self, text: &str) -> Option<Range<usize>> {
+        if self.head.is_none() && self.tail.is_none() && self.bytes.is_none() {
+            return None;
+        }
+        let line_ends = || text.match_indices('\n').map(|(i, _)| i + 1);
+        let mut head = match self.head {
+            // Only a tail was asked for
+            None if self.tail.is_some() => 0,
+            None => text.len(),
+            Some(0) => 0,
+            Some(n) => line_ends().nth(n - 1).unwrap_or(text.len()),
+        };
+        let mut tail = match self.tail {
+            None | Some(0) => text.len(),
+            Some(n) => {
+                // Not counting the newline at the very end
+                let body = text.strip_suffix('\n').unwrap_or(text);
+                body.rmatch_indices('\n')
+                    .nth(n - 1)
+                    .map_or(0, |(i, _)| i + 1)
+            }
+        };
+        if let Some(max) = self.bytes {
+            head = floor_char_boundary(text, head.min(max));
+            let start = text.len().saturating_sub(max).max(tail);
+            tail = (start..=text.len())
+                .find(|&i| text.is_char_boundary(i))
+                .unwrap();
+        }
+        (head < tail).then_some(head..tail)
+    }
+}
+
+fn floor_char_boundary(text: &str, mut i: usize) -> usize {
+    while !text.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+/// Space between the columns of [`Layout::Columns`].
+const GAP: usize = 3;
+
+/// Where blocks go on the line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Everything at the left margin
+    Plain,
+    /// Client on the left, server on the right, each column this wide.
+    /// Longer lines are wrapped.
+    Columns(usize),
+}
+
+impl Layout {
+    /// Two columns filling the given width.
+    pub fn columns(width: usize) -> Layout {
+        Layout::Columns((width.saturating_sub(GAP) / 2).max(20))
+    }
 }
 
 pub struct TextFormatter {
     out: BufWriter<Box<dyn Write + Send>>,
-    force_binary: bool,
+    path: Option<PathBuf>,
+    layout: Layout,
+    preparer: Preparer,
+    split: Option<Split>,
+    /// Where everything goes if there is a queue, see [`TextFormatter::set_queue`]
+    events: Option<Queue>,
+    /// Whether the queue drops messages
+    dropping: Option<Arc<AtomicBool>>,
     in_block: bool,
     at_start: bool,
+    /// Side of the current block
+    side: Side,
+    /// Characters on the current line of the block
+    column: usize,
 }
 
 impl TextFormatter {
@@ -42,10 +175,38 @@ impl TextFormatter {
         let out = BufWriter::new(w);
         TextFormatter {
             out,
-            force_binary: false,
+            path: None,
+            layout: Layout::Plain,
+            preparer: Preparer::default(),
+            split: None,
+            events: None,
+            dropping: None,
             in_block: false,
             at_start: true,
+            side: Side::Client,
+            column: 0,
+        }
+    }
+
+    /// Append to the given file. See also [`TextFormatter::reopen`].
+    pub fn create(path: &Path) -> io::Result<TextFormatter> {
+        let mut formatter = TextFormatter::new(open_output(path)?);
+        formatter.path = Some(path.to_owned());
+        Ok(formatter)
+    }
+
+    /// Reopen the output file, if any. Used after log rotation.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        assert!(!self.in_block);
+        if let Some(events) = &self.events {
+            return events.send(Event::Reopen);
+        }
+        if let Some(path) = &self.path {
+            self.out.flush()?;
+            let file = open_output(path)?;
+            self.out = BufWriter::new(Box::new(file));
         }
+        Ok(())
     }
 
     fn go_to_start(&mut self) -> io::Result<()> {
@@ -58,7 +219,111 @@ impl TextFormatter {
     }
 
     pub fn set_force_binary(&mut self, b: bool) {
-        self.force_binary = b;
+        self.preparer.force_binary = b;
+    }
+
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    /// Leave the formatting and writing to a separate thread, fed through
+    /// a queue of `capacity` bytes of messages. The other settings are
+    /// handed to that thread, so they must come first.
+    pub fn set_queue(&mut self, capacity: usize, when_behind: WhenBehind) -> io::Result<()> {
+        assert!(self.events.is_none());
+        let queue = Queue::new(self.detach(), capacity, when_behind);
+        self.dropping = Some(queue.dropping());
+        self.events = Some(queue);
+        Ok(())
+    }
+
+    /// A formatter that writes where this one did, for the output thread.
+    fn detach(&mut self) -> TextFormatter {
+        let out = mem::replace(&mut self.out, BufWriter::new(Box::new(io::sink())));
+        TextFormatter {
+            out,
+            path: self.path.take(),
+            layout: self.layout,
+            preparer: self.preparer.clone(),
+            ..TextFormatter::new(io::sink())
+        }
+    }
+
+    /// Wait for room in the queue from now on, so nothing more is dropped.
+    pub fn stop_dropping(&self) {
+        if let Some(dropping) = &self.dropping {
+            dropping.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Write everything that is still queued and stop writing.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.events = None;
+        self.out.flush()?;
+        self.out = BufWriter::new(Box::new(io::sink()));
+        Ok(())
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.preparer.limits = limits;
+    }
+
+    /// Append the messages that are not shown in full to the given file.
+    pub fn save_full_to(&mut self, path: &Path) -> io::Result<()> {
+        let full = FullFile {
+            path: path.to_owned(),
+            file: open_output(path)?,
+            count: 0,
+        };
+        self.preparer.full = Some(Arc::new(Mutex::new(full)));
+        Ok(())
+    }
+
+    /// Write the traffic of each connection to its own file in `directory`,
+    /// and list them in its index file.
+    pub fn split_to(&mut self, directory: &Path) -> io::Result<()> {
+        fs::create_dir_all(directory)?;
+        let index = open_output(&directory.join(INDEX))?;
+        self.split = Some(Split {
+            directory: directory.to_owned(),
+            index,
+        });
+        Ok(())
+    }
+
+    /// Move to the column of the side.
+    fn indent(&mut self, side: Side) -> io::Result<()> {
+        if let (Layout::Columns(width), Side::Server) = (self.layout, side) {
+            write!(self.out, "{:1$}", "", width + GAP)?;
+        }
+        Ok(())
+    }
+
+    fn start_line(&mut self) -> io::Result<()> {
+        self.indent(self.side)?;
+        self.out.write_all(boxchars::VERTICAL.as_bytes())?;
+        self.column = 0;
+        Ok(())
+    }
+
+    /// Write part of a line, wrapping at the column width.
+    fn write_wrapped(&mut self, line: &[u8], width: usize) -> io::Result<()> {
+        // The vertical line takes one position
+        let room = width.saturating_sub(1).max(1);
+        let mut start = 0;
+        for (i, &b) in line.iter().enumerate() {
+            let starts_char = b & 0xC0 != 0x80;
+            if starts_char && b != b'\n' {
+                if self.column == room {
+                    self.out.write_all(&line[start..i])?;
+                    self.out.write_all(b"\n")?;
+                    self.start_line()?;
+                    start = i;
+                }
+                self.column += 1;
+            }
+        }
+        self.out.write_all(&line[start..])
     }
 }
 
@@ -72,10 +337,13 @@ impl io::Write for TextFormatter {
         for line in buf.split_inclusive(|b| *b == b'\n') {
             assert!(!line.is_empty());
             if self.at_start {
-                self.out.write_all(boxchars::VERTICAL.as_bytes())?;
+                self.start_line()?;
                 self.at_start = false;
             }
-            self.out.write_all(line)?;
+            match self.layout {
+                Layout::Plain => self.out.write_all(line)?,
+                Layout::Columns(width) => self.write_wrapped(line, width)?,
+            }
             self.at_start = line.ends_with(b"\n");
         }
         Ok(buf.len())
@@ -88,12 +356,29 @@ impl Formatter for TextFormatter {
         client: &dyn fmt::Display,
         server: &dyn fmt::Display,
     ) -> io::Result<()> {
-        writeln!(self.out, "• PROXY {client} to {server}")
+        if let Some(events) = &self.events {
+            return events.send(Event::Connected(client.to_string(), server.to_string()));
+        }
+        writeln!(self.out, "• PROXY {client} to {server}")?;
+        self.flush()
+    }
+
+    fn proxy_message(&mut self, message: &str) -> io::Result<()> {
+        if let Some(events) = &self.events {
+            return events.send(Event::ProxyMessage(message.to_owned()));
+        }
+        assert!(!self.in_block);
+        writeln!(self.out, "• PROXY {message}")?;
+        self.flush()
     }
 
     fn message(&mut self, side: Side, message: &str) -> io::Result<()> {
+        if let Some(events) = &self.events {
+            return events.send(Event::Message(side, message.to_owned()));
+        }
         assert!(!self.in_block);
         assert!(self.at_start);
+        self.indent(side)?;
         writeln!(self.out, "• {side} {message}")?;
         self.flush()
     }
@@ -101,18 +386,21 @@ impl Formatter for TextFormatter {
     fn start_block(&mut self, side: Side, message: &str) -> io::Result<()> {
         assert!(!self.in_block);
         assert!(self.at_start);
+        self.indent(side)?;
         write!(self.out, "{} {side}", boxchars::DOWN_RIGHT)?;
         if !message.is_empty() {
             write!(self.out, " {message}")?;
         }
         writeln!(self.out)?;
         self.in_block = true;
+        self.side = side;
         Ok(())
     }
 
     fn end_block(&mut self) -> io::Result<()> {
         assert!(self.in_block);
         self.go_to_start()?;
+        self.indent(self.side)?;
         self.out.write_all(boxchars::UP_RIGHT.as_bytes())?;
         self.out.write_all(b"\n")?;
         self.flush()?;
@@ -120,11 +408,116 @@ impl Formatter for TextFormatter {
         Ok(())
     }
 
-    fn force_binary(&self) -> bool {
-        self.force_binary
+    fn preparer(&self) -> Preparer {
+        Preparer {
+            deferred: self.events.is_some(),
+            ..self.preparer.clone()
+        }
+    }
+
+    fn print(&mut self, message: Prepared) -> io::Result<()> {
+        if let Some(events) = &self.events {
+            return events.send(Event::Print(message.into_owned()));
+        }
+        let message = message.finish(&self.preparer)?;
+        print_shown(self, &message)
+    }
+
+    fn for_connection(
+        &mut self,
+        id: u64,
+        client: &dyn fmt::Display,
+        server: &dyn fmt::Display,
+    ) -> io::Result<Option<TextFormatter>> {
+        let Some(split) = &mut self.split else {
+            return Ok(None);
+        };
+        let started = Timestamp::now();
+        let client = client.to_string();
+        let safe: String = client
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let name = format!("{id:06}-{safe}-{started:#}.txt");
+        writeln!(split.index, "{id}\t{started}\t{client}\t{server}\t{name}")?;
+        split.index.flush()?;
+
+        let mut formatter = TextFormatter::new(open_output(&split.directory.join(name))?);
+        formatter.layout = self.layout;
+        formatter.preparer = self.preparer.clone();
+        // All connections share the output thread and its queue
+        if let Some(events) = &self.events {
+            let back = formatter.detach();
+            formatter.events = Some(events.open(back)?);
+            formatter.dropping = self.dropping.clone();
+        }
+        Ok(Some(formatter))
+    }
+}
+
+/// Name of the file listing the connections, see [`TextFormatter::split_to`].
+const INDEX: &str = "index.txt";
+
+struct Split {
+    directory: PathBuf,
+    index: File,
+}
+
+/// A moment in UTC in microseconds since the epoch, displayed as
+/// 2006-01-02T15:04:05Z, with a precision as 2006-01-02T15:04:05.123Z, or
+/// with `#` as 20060102-150405 for use in file names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Timestamp(since_epoch.as_micros() as u64)
     }
 }
 
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (secs, micros) = (self.0 / 1_000_000, self.0 % 1_000_000);
+        let (days, secs) = (secs / 86400, secs % 86400);
+        let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+        // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z % 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as u64;
+        if f.alternate() {
+            return write!(f, "{year:04}{month:02}{day:02}-{h:02}{m:02}{s:02}");
+        }
+        write!(f, "{year:04}-{month:02}-{day:02}T{h:02}:{m:02}:{s:02}")?;
+        match f.precision().map(|p| p.min(6)) {
+            None | Some(0) => {}
+            Some(p) => {
+                let fraction = micros / 10u64.pow(6 - p as u32);
+                write!(f, ".{fraction:0p$}")?;
+            }
+        }
+        f.write_str("Z")
+    }
+}
+
+fn open_output(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
 pub fn dump_text(f: &mut dyn Formatter, text: &str) -> io::Result<()> {
     for c in text.chars() {
         match c {
@@ -176,55 +569,582 @@ fn dump_line(f: &mut dyn Formatter, data: &[u8], n: usize) -> io::Result<()> {
     writeln!(f)
 }
 
-pub fn print_message(
-    f: &mut dyn Formatter,
-    side: Side,
-    data: &[u8],
-    remarks: &[&str],
-) -> io::Result<()> {
-    let text = if f.force_binary() {
-        None
-    } else {
-        is_printable_text(data)
-    };
-
-    let n = data.len();
-    let mut msg = if text.is_some() {
-        if data.is_empty() || data.ends_with(b"\n") {
-            format!("text, {n} bytes")
+/// The file that gets the messages that are not shown in full, shared with
+/// the formatters of the connections.
+struct FullFile {
+    path: PathBuf,
+    file: File,
+    /// Number of messages in it
+    count: u64,
+}
+
+impl FullFile {
+    /// Returns a remark saying where the message went.
+    fn save(&mut self, side: Side, data: &[u8]) -> io::Result<String> {
+        self.count += 1;
+        let count = self.count;
+        let mut w = BufWriter::new(&self.file);
+        writeln!(w, "=== #{count} {side}, {n} bytes", n = data.len())?;
+        w.write_all(data)?;
+        writeln!(w)?;
+        w.flush()?;
+        Ok(format!(
+            "full message is #{count} in {}",
+            self.path.display()
+        ))
+    }
+}
+
+/// Works out how to print messages, see [`Formatter::preparer`].
+#[derive(Clone, Default)]
+pub struct Preparer {
+    /// Leave the preparing to the output thread, see [`TextFormatter::set_queue`]
+    deferred: bool,
+    force_binary: bool,
+    limits: Limits,
+    full: Option<Arc<Mutex<FullFile>>>,
+}
+
+impl Preparer {
+    /// How many bytes at either end of a message are enough to print it,
+    /// if the middle does not have to be kept, see [`Collector`].
+    pub fn keep(&self) -> Option<usize> {
+        if self.full.is_some() {
+            return None;
+        }
+        let binary = self.limits.binary_max();
+        if self.force_binary {
+            return binary;
+        }
+        Some(self.limits.bytes?.max(binary?))
+    }
+
+    /// Decide what to show of the message. If that is not all of it, it is
+    /// saved to the full file.
+    pub fn prepare<'a>(
+        &self,
+        side: Side,
+        message: Message<'a>,
+        remarks: &[&str],
+    ) -> io::Result<Prepared<'a>> {
+        if self.deferred {
+            let remarks = remarks.iter().map(|r| r.to_string()).collect();
+            return Ok(Prepared(Stage::Deferred(side, message, remarks)));
+        }
+        self.show(side, message, remarks)
+            .map(|shown| Prepared(Stage::Ready(shown)))
+    }
+
+    fn show<'a>(
+        &self,
+        side: Side,
+        message: Message<'a>,
+        remarks: &[&str],
+    ) -> io::Result<Shown<'a>> {
+        let Message { data, gap } = message;
+        let gap_len = gap.as_ref().map_or(0, |g| g.len);
+        let n = data.len() + gap_len;
+        let text = if self.force_binary {
+            None
+        } else {
+            is_printable_text(&data).filter(|_| gap.as_ref().is_none_or(Gap::printable))
+        };
+
+        let omit = match text {
+            Some(t) => self.limits.omit(t),
+            None => self
+                .limits
+                .binary_max()
+                .filter(|&max| max < n)
+                .map(|max| max..data.len()),
+        };
+        // Both ends are kept, so the middle is always left out
+        let omit = match &gap {
+            Some(gap) => Some(omit.unwrap_or(gap.at..gap.at)),
+            None => omit,
+        };
+        let saved = match (&omit, &self.full) {
+            (Some(_), Some(full)) => Some(full.lock().unwrap().save(side, &data)?),
+            _ => None,
+        };
+
+        let mut header = if text.is_some() {
+            if data.is_empty() || data.ends_with(b"\n") {
+                format!("text, {n} bytes")
+            } else {
+                format!("text, {n} bytes, no trailing newline")
+            }
         } else {
-            format!("text, {n} bytes, no trailing newline")
+            format!("binary, {n} bytes")
+        };
+        for r in remarks.iter().copied().chain(saved.as_deref()) {
+            header.push_str(", ");
+            header.push_str(r);
         }
-    } else {
-        format!("binary, {n} bytes")
-    };
-    for r in remarks {
-        msg.push_str(", ");
-        msg.push_str(r);
+
+        let footer = match (text, &omit) {
+            (_, None) => None,
+            (Some(t), Some(omit)) => {
+                let at = gap.as_ref().map_or(omit.end, |g| g.at);
+                let mut lines = Lines::default();
+                lines.feed(&t.as_bytes()[omit.start..at]);
+                if let Some(gap) = &gap {
+                    lines.skip(gap);
+                }
+                lines.feed(&t.as_bytes()[at..omit.end]);
+                let n = omit.len() + gap_len;
+                Some(match (lines.rows, lines.lines) {
+                    (0, 0) => format!("… {n} more bytes"),
+                    (0, lines) => format!("… {n} more bytes / {lines} more lines"),
+                    (rows, _) => format!("… {n} more bytes / {rows} more rows"),
+                })
+            }
+            (None, Some(omit)) => Some(format!("… {} more bytes", omit.len() + gap_len)),
+        };
+        let text = text.is_some();
+
+        Ok(Shown {
+            side,
+            data,
+            text,
+            omit,
+            header,
+            footer,
+        })
     }
+}
+
+/// A message to be printed, possibly without its middle.
+pub struct Message<'a> {
+    data: Cow<'a, [u8]>,
+    /// Where the middle was left out of `data`
+    gap: Option<Gap>,
+}
 
-    f.start_block(side, &msg)?;
-    if let Some(t) = text {
-        dump_text(f, t)?;
-    } else {
-        dump_binary(f, data)?;
+impl Message<'_> {
+    /// What has been kept of the message.
+    pub fn data(&self) -> &[u8] {
+        &self.data
     }
-    f.end_block()?;
 
-    Ok(())
+    fn into_owned(self) -> Message<'static> {
+        Message {
+            data: Cow::Owned(self.data.into_owned()),
+            gap: self.gap,
+        }
+    }
+}
+
+impl<'a> From<&'a [u8]> for Message<'a> {
+    fn from(data: &'a [u8]) -> Message<'a> {
+        Message {
+            data: Cow::Borrowed(data),
+            gap: None,
+        }
+    }
+}
+
+/// What is known about the middle of a message that has not been kept.
+#[derive(Debug, Default)]
+struct Gap {
+    /// Where it was, as an index in the data that was kept
+    at: usize,
+    len: usize,
+    first: Option<u8>,
+    /// Counted as if the gap starts halfway a line
+    lines: Lines,
+    /// Incomplete UTF-8 sequence at the end of what has been seen so far
+    pending: Vec<u8>,
+    binary: bool,
+}
+
+impl Gap {
+    fn feed(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.first.get_or_insert(data[0]);
+        self.len += data.len();
+        self.lines.feed(data);
+        if self.binary {
+            return;
+        }
+        self.pending.extend_from_slice(data);
+        let valid = match from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => {
+                self.binary = true;
+                return;
+            }
+        };
+        let text = from_utf8(&self.pending[..valid]).unwrap();
+        self.binary = !is_printable(text);
+        self.pending.drain(..valid);
+    }
+
+    fn printable(&self) -> bool {
+        !self.binary && self.pending.is_empty()
+    }
+}
+
+/// Counts lines and the rows of a result set among them.
+#[derive(Debug)]
+struct Lines {
+    lines: usize,
+    rows: usize,
+    at_line_start: bool,
+}
+
+impl Default for Lines {
+    fn default() -> Lines {
+        Lines {
+            lines: 0,
+            rows: 0,
+            at_line_start: true,
+        }
+    }
+}
+
+impl Lines {
+    fn feed(&mut self, data: &[u8]) {
+        for &b in data {
+            if self.at_line_start && b == b'[' {
+                self.rows += 1;
+            }
+            self.at_line_start = b == b'\n';
+            self.lines += self.at_line_start as usize;
+        }
+    }
+
+    /// Count the lines in a gap as if it had been fed.
+    fn skip(&mut self, gap: &Gap) {
+        if self.at_line_start && gap.first == Some(b'[') {
+            self.rows += 1;
+        }
+        self.rows += gap.lines.rows;
+        self.lines += gap.lines.lines;
+        if gap.len > 0 {
+            self.at_line_start = gap.lines.at_line_start;
+        }
+    }
+}
+
+/// Collects the blocks of a message. Given a size from [`Preparer::keep`],
+/// only that much of either end of a long message is kept.
+#[derive(Default)]
+pub struct Collector {
+    keep: Option<usize>,
+    head: Vec<u8>,
+    tail: Vec<u8>,
+    gap: Option<Gap>,
+}
+
+impl Collector {
+    pub fn new(keep: Option<usize>) -> Collector {
+        Collector {
+            keep,
+            ..Collector::default()
+        }
+    }
+
+    pub fn push(&mut self, mut data: &[u8]) {
+        let Some(keep) = self.keep else {
+            self.head.extend_from_slice(data);
+            return;
+        };
+        if self.tail.is_empty() && self.gap.is_none() {
+            // The head ends at a character boundary
+            let mut n = keep.saturating_sub(self.head.len()).min(data.len());
+            while n < data.len() && is_continuation(data[n]) {
+                n += 1;
+            }
+            self.head.extend_from_slice(&data[..n]);
+            data = &data[n..];
+        }
+        self.tail.extend_from_slice(data);
+        if self.tail.len() > 2 * keep {
+            let n = self.tail.len() - keep;
+            self.gap.get_or_insert_default().feed(&self.tail[..n]);
+            self.tail.drain(..n);
+        }
+    }
+
+    /// The message collected so far, the collector is empty afterwards.
+    pub fn take(&mut self) -> Message<'static> {
+        let mut data = mem::take(&mut self.head);
+        let mut tail = mem::take(&mut self.tail);
+        let mut gap = self.gap.take();
+        if let Some(gap) = &mut gap {
+            // The tail starts at a character boundary too
+            let n = tail.iter().take_while(|&&b| is_continuation(b)).count();
+            gap.feed(&tail[..n]);
+            tail.drain(..n);
+            gap.at = data.len();
+        }
+        data.extend_from_slice(&tail);
+        Message {
+            data: Cow::Owned(data),
+            gap,
+        }
+    }
+}
+
+fn is_continuation(b: u8) -> bool {
+    b & 0xC0 == 0x80
+}
+
+/// A message for [`Formatter::print`].
+pub struct Prepared<'a>(Stage<'a>);
+
+enum Stage<'a> {
+    Ready(Shown<'a>),
+    /// The message with its remarks, see [`Preparer::deferred`]
+    Deferred(Side, Message<'a>, Vec<String>),
+}
+
+impl<'a> Prepared<'a> {
+    pub fn into_owned(self) -> Prepared<'static> {
+        Prepared(match self.0 {
+            Stage::Ready(shown) => Stage::Ready(Shown {
+                data: Cow::Owned(shown.data.into_owned()),
+                ..shown
+            }),
+            Stage::Deferred(side, message, remarks) => {
+                Stage::Deferred(side, message.into_owned(), remarks)
+            }
+        })
+    }
+
+    /// Bytes of the message that have been kept.
+    pub fn size(&self) -> usize {
+        match &self.0 {
+            Stage::Ready(shown) => shown.data.len(),
+            Stage::Deferred(_, message, _) => message.data.len(),
+        }
+    }
+
+    fn finish(self, preparer: &Preparer) -> io::Result<Shown<'a>> {
+        match self.0 {
+            Stage::Ready(shown) => Ok(shown),
+            Stage::Deferred(side, message, remarks) => {
+                let remarks: Vec<&str> = remarks.iter().map(String::as_str).collect();
+                preparer.show(side, message, &remarks)
+            }
+        }
+    }
+}
+
+/// What to show of a message.
+struct Shown<'a> {
+    side: Side,
+    data: Cow<'a, [u8]>,
+    text: bool,
+    /// What is left out of `data`, where the gap in it is
+    omit: Option<Range<usize>>,
+    header: String,
+    /// Says what has been left out
+    footer: Option<String>,
+}
+
+fn print_shown(f: &mut dyn Formatter, message: &Shown) -> io::Result<()> {
+    f.start_block(message.side, &message.header)?;
+    let data = &message.data[..];
+    let text = message.text.then(|| from_utf8(data).unwrap_or_default());
+    let footer = message.footer.as_deref().unwrap_or_default();
+    match (text, &message.omit) {
+        (Some(t), None) => dump_text(f, t)?,
+        (None, None) => dump_binary(f, data)?,
+        (Some(t), Some(omit)) => {
+            let (head, tail) = (&t[..omit.start], &t[omit.end..]);
+            dump_text(f, head)?;
+            if !head.is_empty() && !head.ends_with('\n') {
+                writeln!(f)?;
+            }
+            writeln!(f, "{footer}")?;
+            dump_text(f, tail)?;
+        }
+        (None, Some(omit)) => {
+            dump_binary(f, &data[..omit.start])?;
+            writeln!(f, "{footer}")?;
+        }
+    }
+    f.end_block()
 }
 
 fn is_printable_text(data: &[u8]) -> Option<&str> {
-    if let Ok(text) = from_utf8(data) {
-        let scary = text
-            .chars()
-            .find(|&c| c.is_control() && c != '\n' && c != '\t');
-        if scary.is_some() {
-            None
-        } else {
-            Some(text)
+    from_utf8(data).ok().filter(|text| is_printable(text))
+}
+
+fn is_printable(text: &str) -> bool {
+    !text
+        .chars()
+        .any(|c| c.is_control() && c != '\n' && c != '\t')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shown(limits: Limits, text: &str) -> String {
+        match limits.omit(text) {
+            Some(omit) => format!("{}|{}", &text[..omit.start], &text[omit.end..]),
+            None => text.to_string(),
         }
-    } else {
-        None
+    }
+
+    #[test]
+    fn test_omit() {
+        let text = "a\nb\nc\nd\n";
+        let limits = |head, tail, bytes| Limits {
+            head,
+            tail,
+            bytes,
+            binary: None,
+        };
+        assert_eq!(shown(limits(None, None, None), text), text);
+        assert_eq!(shown(limits(Some(1), None, None), text), "a\n|");
+        assert_eq!(shown(limits(None, Some(1), None), text), "|d\n");
+        assert_eq!(shown(limits(Some(1), Some(2), None), text), "a\n|c\nd\n");
+        assert_eq!(shown(limits(Some(2), Some(2), None), text), text);
+        assert_eq!(shown(limits(Some(9), None, None), text), text);
+        assert_eq!(shown(limits(None, None, Some(3)), text), "a\nb|");
+        assert_eq!(shown(limits(None, Some(1), Some(1)), text), "|\n");
+        assert_eq!(shown(limits(Some(1), Some(1), Some(1)), text), "a|\n");
+        // Never in the middle of a character
+        assert_eq!(shown(limits(None, None, Some(2)), "éé"), "é|");
+        assert_eq!(shown(limits(None, None, Some(1)), "éé"), "|");
+        assert_eq!(shown(limits(None, Some(1), Some(3)), "ééé\néé"), "|é");
+    }
+
+    fn printed(preparer: &Preparer, message: Message) -> (String, Option<String>, String) {
+        let shown = preparer.show(Side::Server, message, &[]).unwrap();
+        let data = match &shown.omit {
+            Some(omit) => [&shown.data[..omit.start], b"|", &shown.data[omit.end..]].concat(),
+            None => shown.data.to_vec(),
+        };
+        (
+            shown.header,
+            shown.footer,
+            String::from_utf8_lossy(&data).into_owned(),
+        )
+    }
+
+    #[test]
+    fn test_collector() {
+        let text: String = (0..200).map(|i| format!("[ \"é{i}€\"\t]\n")).collect();
+        let binary: Vec<u8> = (0..=255).cycle().take(3000).collect();
+        for limits in [
+            (None, None, Some(100)),
+            (Some(3), None, Some(100)),
+            (None, Some(2), Some(100)),
+            (Some(3), Some(2), Some(500)),
+        ]
+        .map(|(head, tail, bytes)| Limits {
+            head,
+            tail,
+            bytes,
+            binary: Some(64),
+        }) {
+            let preparer = Preparer {
+                limits,
+                ..Preparer::default()
+            };
+            for data in [text.as_bytes(), &binary] {
+                let mut collector = Collector::new(preparer.keep());
+                for block in data.chunks(97) {
+                    collector.push(block);
+                }
+                let message = collector.take();
+                assert!(message.gap.is_some());
+                assert_eq!(
+                    printed(&preparer, message),
+                    printed(&preparer, data.into()),
+                    "{limits:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_collector_max_bytes() {
+        let text: String = (0..2000).map(|i| format!("line {i}\n")).collect();
+        let binary: Vec<u8> = (0..=255).cycle().take(30000).collect();
+        let preparer = Preparer {
+            limits: Limits {
+                bytes: Some(100),
+                ..Limits::default()
+            },
+            ..Preparer::default()
+        };
+        assert_eq!(preparer.keep(), Some(100));
+        for data in [text.as_bytes(), &binary] {
+            let mut collector = Collector::new(preparer.keep());
+            for block in data.chunks(97) {
+                collector.push(block);
+            }
+            assert!(collector.head.len() + collector.tail.len() <= 3 * 100);
+            let message = collector.take();
+            assert!(message.gap.is_some());
+            let shown = printed(&preparer, message);
+            assert_eq!(shown, printed(&preparer, data.into()));
+            assert!(shown.2.len() < 1000, "{shown:?}");
+        }
+    }
+
+    #[test]
+    fn test_split_queue() {
+        let dir = std::env::temp_dir().join(format!("monetproxy-split-{}", std::process::id()));
+        let mut main = TextFormatter::new(io::sink());
+        main.split_to(&dir).unwrap();
+        main.set_queue(1000, WhenBehind::Wait).unwrap();
+        let mut first = main
+            .for_connection(1, &"client1", &"server")
+            .unwrap()
+            .unwrap();
+        let mut second = main
+            .for_connection(2, &"client2", &"server")
+            .unwrap()
+            .unwrap();
+        first.proxy_message("to the first").unwrap();
+        second.proxy_message("to the second").unwrap();
+        drop(first);
+        // The output thread closes the files that are still open
+        main.close().unwrap();
+        assert!(second.proxy_message("too late").is_err());
+        drop(second);
+
+        let mut files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| !path.ends_with(INDEX))
+            .collect();
+        files.sort();
+        let contents: Vec<_> = files
+            .iter()
+            .map(|f| fs::read_to_string(f).unwrap())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            contents,
+            ["• PROXY to the first\n", "• PROXY to the second\n"]
+        );
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let second = 1_000_000;
+        assert_eq!(Timestamp(0).to_string(), "1970-01-01T00:00:00Z");
+        let leap_day = Timestamp(951_868_799 * second + 123_456);
+        assert_eq!(leap_day.to_string(), "2000-02-29T23:59:59Z");
+        assert_eq!(format!("{leap_day:.3}"), "2000-02-29T23:59:59.123Z");
+        assert_eq!(format!("{leap_day:.6}"), "2000-02-29T23:59:59.123456Z");
+        assert_eq!(format!("{leap_day:#}"), "20000229-235959");
+        let next = Timestamp(leap_day.0 + second);
+        assert_eq!(next.to_string(), "2000-03-01T00:00:00Z");
+        assert_eq!(
+            Timestamp(1_136_214_245 * second).to_string(),
+            "2006-01-02T15:04:05Z"
+        );
     }
 }
@@ -1,51 +1,267 @@
+mod browser;
+mod capture;
+mod connections;
+mod debugger;
+mod events;
+mod filter;
+mod firewall;
 mod formatter;
 mod network;
 mod observers;
+mod output;
 mod proxy;
+mod signals;
+#[cfg(target_os = "linux")]
+mod splice;
+mod sql;
+mod tamper;
+mod tls;
 
-use anyhow::Result as AResult;
+use anyhow::{Context, Result as AResult};
 use argsplitter::{ArgError, ArgSplitter};
-use formatter::TextFormatter;
-use network::Address;
+use capture::{Recorder, Store, LIVE_LIMIT};
+use connections::{Connections, Timeouts, WhenFull};
+use debugger::Debugger;
+use filter::Filter;
+use firewall::DenyRule;
+use formatter::{Formatter, Layout, Limits, Side, TextFormatter};
+use network::{Activation, Address, UnixOptions};
+use output::WhenBehind;
+use regex::Regex;
+use signals::Signal;
+use std::fs::File;
 use std::io;
-use std::net::ToSocketAddrs;
+use std::path::PathBuf;
 use std::process::ExitCode;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use observers::{BlockObserver, MessageObserver, RawObserver};
-use proxy::spawn_listener;
+use events::spawn_event_loop;
+use observers::{BlockObserver, MessageObserver, RawObserver, TransferObserver};
+use proxy::{spawn_listener, Destination};
+use tamper::{
+    ChaosAction, ChaosRule, CorruptMode, Corruption, InjectRule, RateLimit, RewriteRule,
+    TamperConfig,
+};
+use tls::{TlsSettings, Verify};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Width of the side-by-side layout when not writing to a terminal.
+const DEFAULT_WIDTH: usize = 160;
+
 const USAGE: &str = "\
 Usage:  monetproxy [OPTION..] LISTEN_ADDR DEST_ADDR
-        (ADDR is PORT or HOST:PORT or ../PATH/TO/SOCKET)
+        monetproxy --browse=FILE
+Addresses:
+    PORT                Unix socket /tmp/.s.monetdb.PORT and TCP localhost:PORT
+    HOST:PORT           TCP, HOST is a host name, an IPv4 address, a bracketed
+                        IPv6 address like [::1], or * for all interfaces
+    tcp:HOST:PORT       Same, tcp4: and tcp6: restrict the address family
+    tcp:PORT            Same as tcp:localhost:PORT
+    unix:PATH           Unix socket, the prefix is optional if PATH contains a '/'
+    @NAME               Abstract Unix socket (Linux only), also unix:@NAME
+    fd:N                Listen on inherited file descriptor N
+    systemd[:NAME]      Listen on the sockets passed by systemd socket activation,
+                        or only those with FileDescriptorName=NAME
+    When listening, a HOST name binds only its first address.
 Options:
-    -h --help       Show help
-    -r --raw        Dump raw bytes
-    -b --blocks     Dump blocks
-    -m --messages   Dump messages (default)
-    -B --binary     Force binary dump
-    -v --version    Show version information
+    -h --help           Show help
+    -r --raw            Dump raw bytes
+    -b --blocks         Dump blocks
+    -m --messages       Dump messages (default)
+    -B --binary         Force binary dump
+    --only=SIDE         Only print the messages from 'client' or 'server'
+    --kind=KINDS        Only print messages of these kinds, a comma separated list
+                        of 'sql' and 'command' from the client, 'query', 'update',
+                        'schema', 'transaction', 'prepare', 'block', 'error',
+                        'prompt' and 'redirect' from the server, and 'other'
+    -e --errors         Only print error replies, same as --kind=error
+    --match=REGEX       Only print messages matching REGEX, may be given more than
+                        once. For SQL the leading 's' is not matched
+                        These filters work on whole messages and need --messages.
+                        The traffic itself is not affected
+    --head=N            Only print the first N lines of each text message
+    --tail=N            Only print the last N lines of each text message, together
+                        with --head the lines in between are left out
+    --max-bytes=N       Print at most N bytes from the start of each text message,
+                        and with --tail also at most N from the end; binary
+                        messages too, unless --max-binary is given
+    --max-binary=N      Print at most the first N bytes of each binary message
+    --split=DIR         Write the traffic of each connection to its own file in DIR,
+                        named after the connection number, client address and
+                        start time, and list the connections in DIR/index.txt
+    --full-to=FILE      Append the messages that are not printed in full to FILE
+    -s --side-by-side   Show client messages on the left and server messages on
+                        the right, wrapping long lines
+    --width=N           Width of the side-by-side layout, default is the width
+                        of the terminal
+    -p --pass-through   Do not look at the data, only report how much was forwarded
+                        and when. On Linux with --engine=threads the data is moved
+                        using splice(2) and never copied into the proxy
+    -o --output=FILE    Append to FILE instead of writing to stdout,
+                        the file is reopened on SIGHUP
+    --output-queue=N    Format and write the output on a separate thread, with a
+                        queue of up to N bytes of messages, so slow output does
+                        not slow down the traffic. A larger message waits until
+                        the queue is empty. With --split all connections share
+                        the thread and the queue
+    --when-behind=WHAT  What to do when the output queue is full: 'wait' for room
+                        (default) or 'drop' messages and report how many
+    --tui               Browse the connections in a full-screen view instead of
+                        writing to stdout
+    --record=FILE       Save everything that passes through the proxy to capture
+                        file FILE
+    --browse=FILE       Browse capture file FILE in a full-screen view
+    --drain=SECS        On SIGINT or SIGTERM, give open connections SECS seconds
+                        to finish before closing them
+    --engine=ENGINE     How to move the data: 'threads' uses two threads per
+                        connection (default), 'events' handles all connections
+                        in a single thread. TLS requires 'threads'
+    --max-connections=N Accept at most N connections at a time
+    --max-per-listener=N
+                        Same, for each listening socket separately
+    --when-full=WHAT    What to do with new clients when the maximum has been
+                        reached: 'refuse' sends them an error (default), 'queue'
+                        leaves them waiting until a connection closes
+    --idle-timeout=SECS Close connections without traffic in either direction
+                        for SECS seconds
+    --max-lifetime=SECS Close connections after SECS seconds
+    --socket-mode=MODE  Permissions of the Unix socket files we create, in octal
+    --socket-group=GRP  Group of the Unix socket files we create
+    -v --version        Show version information
+Tampering options, these require --engine=threads:
+    --delay=MS          Delay each message by MS milliseconds
+    --jitter=MS         Add a random delay of up to MS milliseconds
+    --delay-side=SIDE   Only delay messages from 'client' or 'server'
+    --delay-match=REGEX Only delay client messages matching REGEX, and the server's
+                        replies to them. For SQL the leading 's' is not matched
+    --upload-rate=RATE  Forward at most RATE bytes per second from client to server.
+                        RATE may have a suffix k, M or G
+    --download-rate=RATE
+                        Same, from server to client
+    --shared-rate       The rates apply to all connections together instead
+                        of to each connection
+    --chaos=ACTION      Break the connection once the --chaos-* triggers below have
+                        all fired: 'close' it, 'reset' it, 'truncate' a block
+                        halfway, or 'stall' and stop forwarding
+    --chaos-side=SIDE   Count and act on the data from this side, default 'server'
+    --chaos-after-bytes=N
+                        Trigger after N bytes
+    --chaos-after-messages=N
+                        Trigger after N messages
+    --chaos-query=REGEX Trigger in the server's reply to client messages matching
+                        REGEX, bytes and messages are counted from the start of
+                        the reply
+    --chaos-probability=P
+                        Trigger at the start of each message with probability P
+    --corrupt=MODES     Damage the blocks sent by the server, MODES is a comma
+                        separated list of 'length' (wrong length in the header),
+                        'no-last' (clear the last-block flag), 'bitflip' (in the
+                        body) and 'no-prompt' (drop the block ending the message).
+                        One of them is picked at random for each block. The
+                        output shows the blocks as the server sent them
+    --corrupt-probability=P
+                        Only damage each block with probability P, default 1
+    --inject-error=SQLSTATE!MESSAGE
+                        Do not forward client messages matching --inject-match,
+                        answer them with error SQLSTATE!MESSAGE instead
+    --inject-match=REGEX
+                        Which client messages to answer with --inject-error.
+                        For SQL the leading 's' is not matched
+    --rewrite=REGEX=>REPLACEMENT
+                        Replace all matches of REGEX in the SQL sent by the client.
+                        REPLACEMENT may refer to groups as $1 or ${name}.
+                        Can be given more than once, applied in order
+    --replace=TEXT=>REPLACEMENT
+                        Same, but TEXT and REPLACEMENT are taken literally
+    --allow=KINDS       Only forward SQL statements of these kinds, a comma
+                        separated list of first keywords like select,with,explain.
+                        Blocked messages are answered with an error, every decision
+                        is logged
+    --deny=REGEX        Do not forward SQL statements matching REGEX. Comments are
+                        removed and white space is collapsed before matching.
+                        Can be given more than once
+    --unless=REGEX      Make an exception to the preceding --deny for statements
+                        that also match REGEX
+    --read-only         Only forward statements that do not modify data or schema,
+                        and commands that only affect how results are delivered.
+                        PREPARE is judged by the statement it prepares. Once
+                        logged in, other kinds of messages are refused
+    --step              Hold every message and ask on the terminal whether to
+                        forward, drop or edit it, or to send another one first
+TLS options, these only apply to the connection to DEST_ADDR. Clients cannot
+connect to the proxy using TLS:
+    -t --tls            Connect to DEST_ADDR using TLS, implied by the options below
+    --tls-ca=FILE       Verify the server using the CA certificates in FILE
+                        instead of the system certificates
+    --tls-no-verify     Accept any server certificate
+    --tls-sni=NAME      Server name to send and verify, default is the host of
+                        DEST_ADDR
+    --tls-no-sni        Do not send a server name
+    --tls-cert=FILE     Present the client certificate in FILE
+    --tls-key=FILE      Private key of the client certificate, default is to
+                        look in the --tls-cert file
 ";
 
 fn main() -> ExitCode {
     argsplitter::main_support::report_errors(USAGE, mymain())
 }
 
+#[derive(Debug, PartialEq, Eq)]
+enum Engine {
+    Threads,
+    Events,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum Observe {
     Raw,
     Blocks,
     Messages,
+    PassThrough,
 }
 
 fn mymain() -> AResult<()> {
+    // Before any threads are started
+    let activation = Activation::take();
     let mut args = ArgSplitter::from_env();
     let mut observe = Observe::Messages;
     let mut force_binary = false;
+    let mut side_by_side = false;
+    let mut width = None;
+    let mut filter = Filter::default();
+    let mut limits = Limits::default();
+    let mut full_to: Option<PathBuf> = None;
+    let mut split: Option<PathBuf> = None;
+    let mut output: Option<PathBuf> = None;
+    let mut output_queue = None;
+    let mut when_behind = WhenBehind::Wait;
+    let mut tui = false;
+    let mut record: Option<PathBuf> = None;
+    let mut browse: Option<PathBuf> = None;
+    let mut drain = Duration::ZERO;
+    let mut engine = Engine::Threads;
+    let mut max_connections = None;
+    let mut max_per_listener = None;
+    let mut when_full = WhenFull::Refuse;
+    let mut timeouts = Timeouts::default();
+    let mut tamper = TamperConfig::default();
+    let mut upload_rate = None;
+    let mut download_rate = None;
+    let mut shared_rate = false;
+    let mut chaos_action = None;
+    let mut corrupt_modes = vec![];
+    let mut corrupt_probability = 1.0;
+    let mut inject_error = None;
+    let mut inject_match = None;
+    let mut use_tls = false;
+    let mut tls_settings = TlsSettings::default();
+    let mut unix_options = UnixOptions::default();
     while let Some(flag) = args.flag()? {
+        use_tls |= flag.starts_with("--tls");
         match flag {
             "-h" | "--help" => {
                 println!("{USAGE}");
@@ -54,7 +270,125 @@ fn mymain() -> AResult<()> {
             "-r" | "--raw" => observe = Observe::Raw,
             "-b" | "--blocks" => observe = Observe::Blocks,
             "-m" | "--messages" => observe = Observe::Messages,
+            "-p" | "--pass-through" => observe = Observe::PassThrough,
             "-B" | "--binary" => force_binary = true,
+            "-s" | "--side-by-side" => side_by_side = true,
+            "--width" => width = Some(parse_count("--width", &args.param()?)?),
+            "--only" => filter.side = Some(parse_side(&args.param()?)?),
+            "--kind" => filter.kinds.extend(parse_kinds(&args.param()?)?),
+            "-e" | "--errors" => filter.kinds.push("error"),
+            "--match" => filter.matching.push(parse_regex(&args.param()?)?),
+            "--head" => limits.head = Some(parse_number(&args.param()?)? as usize),
+            "--tail" => limits.tail = Some(parse_number(&args.param()?)? as usize),
+            "--max-bytes" => limits.bytes = Some(parse_number(&args.param()?)? as usize),
+            "--max-binary" => limits.binary = Some(parse_number(&args.param()?)? as usize),
+            "--full-to" => full_to = Some(args.param_os()?.into()),
+            "--split" => split = Some(args.param_os()?.into()),
+            "-o" | "--output" => output = Some(args.param_os()?.into()),
+            "--output-queue" => output_queue = Some(parse_count("--output-queue", &args.param()?)?),
+            "--when-behind" => when_behind = parse_when_behind(&args.param()?)?,
+            "--tui" => tui = true,
+            "--record" => record = Some(args.param_os()?.into()),
+            "--browse" => browse = Some(args.param_os()?.into()),
+            "--drain" => drain = Duration::from_secs(parse_number(&args.param()?)?),
+            "--engine" => engine = parse_engine(&args.param()?)?,
+            "--max-connections" => {
+                max_connections = Some(parse_count("--max-connections", &args.param()?)?)
+            }
+            "--max-per-listener" => {
+                max_per_listener = Some(parse_count("--max-per-listener", &args.param()?)?)
+            }
+            "--idle-timeout" => timeouts.idle = Some(parse_timeout(&args.param()?)?),
+            "--max-lifetime" => timeouts.lifetime = Some(parse_timeout(&args.param()?)?),
+            "--when-full" => when_full = parse_when_full(&args.param()?)?,
+            "--delay" => tamper.delay.get_or_insert_default().fixed = parse_millis(&args.param()?)?,
+            "--jitter" => {
+                tamper.delay.get_or_insert_default().jitter = parse_millis(&args.param()?)?
+            }
+            "--delay-side" => {
+                tamper.delay.get_or_insert_default().side = Some(parse_side(&args.param()?)?)
+            }
+            "--delay-match" => {
+                tamper.delay.get_or_insert_default().matching = Some(parse_regex(&args.param()?)?)
+            }
+            "--upload-rate" => upload_rate = Some(parse_rate(&args.param()?)?),
+            "--download-rate" => download_rate = Some(parse_rate(&args.param()?)?),
+            "--shared-rate" => shared_rate = true,
+            "--chaos" => chaos_action = Some(parse_chaos_action(&args.param()?)?),
+            "--chaos-side" => {
+                tamper.chaos.get_or_insert_default().side = parse_side(&args.param()?)?
+            }
+            "--chaos-after-bytes" => {
+                tamper.chaos.get_or_insert_default().after_bytes =
+                    Some(parse_number(&args.param()?)?)
+            }
+            "--chaos-after-messages" => {
+                tamper.chaos.get_or_insert_default().after_messages =
+                    Some(parse_number(&args.param()?)?)
+            }
+            "--chaos-query" => {
+                tamper.chaos.get_or_insert_default().query = Some(parse_regex(&args.param()?)?)
+            }
+            "--chaos-probability" => {
+                tamper.chaos.get_or_insert_default().probability =
+                    Some(parse_probability(&args.param()?)?)
+            }
+            "--corrupt" => corrupt_modes = parse_corrupt_modes(&args.param()?)?,
+            "--corrupt-probability" => corrupt_probability = parse_probability(&args.param()?)?,
+            "--inject-error" => inject_error = Some(parse_error(&args.param()?)?),
+            "--inject-match" => inject_match = Some(parse_regex(&args.param()?)?),
+            "--allow" => {
+                let kinds = args
+                    .param()?
+                    .split(',')
+                    .map(|kind| kind.trim().to_uppercase())
+                    .collect();
+                tamper.firewall.get_or_insert_default().allow = Some(kinds);
+            }
+            "--step" => tamper.debugger = Some(Debugger::default()),
+            "--read-only" => tamper.firewall.get_or_insert_default().read_only = true,
+            "--deny" => {
+                let pattern = parse_regex(&args.param()?)?;
+                tamper.firewall.get_or_insert_default().deny.push(DenyRule {
+                    pattern,
+                    unless: None,
+                });
+            }
+            "--unless" => {
+                let unless = parse_regex(&args.param()?)?;
+                match tamper.firewall.as_mut().and_then(|f| f.deny.last_mut()) {
+                    Some(rule) if rule.unless.is_none() => rule.unless = Some(unless),
+                    _ => {
+                        return Err(
+                            ArgError::message("every --unless must follow its own --deny").into(),
+                        )
+                    }
+                }
+            }
+            "--rewrite" => {
+                let (pattern, replacement) = parse_substitution(&args.param()?)?;
+                let pattern = parse_regex(&pattern)?;
+                tamper.rewrite.push(RewriteRule {
+                    pattern,
+                    replacement,
+                    literal: false,
+                });
+            }
+            "--replace" => {
+                let (text, replacement) = parse_substitution(&args.param()?)?;
+                tamper
+                    .rewrite
+                    .push(RewriteRule::literal(&text, replacement));
+            }
+            "--socket-mode" => unix_options.mode = Some(parse_mode(&args.param()?)?),
+            "--socket-group" => unix_options.group = Some(network::lookup_group(&args.param()?)?),
+            "-t" | "--tls" => use_tls = true,
+            "--tls-ca" => tls_settings.ca_file = Some(args.param_os()?.into()),
+            "--tls-no-verify" => tls_settings.verify = Verify::None,
+            "--tls-sni" => tls_settings.sni = Some(args.param()?),
+            "--tls-no-sni" => tls_settings.send_sni = false,
+            "--tls-cert" => tls_settings.client_cert = Some(args.param_os()?.into()),
+            "--tls-key" => tls_settings.client_key = Some(args.param_os()?.into()),
             "-v" | "--version" => {
                 println!("Monetproxy {VERSION}");
                 return Ok(());
@@ -62,42 +396,507 @@ fn mymain() -> AResult<()> {
             _ => Err(ArgError::unknown_flag(flag))?,
         }
     }
+    if let Some(path) = browse {
+        args.no_more_stashed()?;
+        let store =
+            Store::load(&path).with_context(|| format!("could not load {}", path.display()))?;
+        browser::run(Arc::new(Mutex::new(store)), || false)?;
+        return Ok(());
+    }
     let listen_addr = Address::parse(&args.stashed("LISTEN_ADDR")?)?;
     let forward_addr = Address::parse(&args.stashed("DEST_ADDR")?)?;
     args.no_more_stashed()?;
+    if let Address::Fd(_) | Address::Systemd(_) = forward_addr {
+        let msg = format!("DEST_ADDR {forward_addr} can only be used to listen");
+        return Err(ArgError::message(msg).into());
+    }
+
+    if !filter.is_empty() && observe != Observe::Messages {
+        return Err(
+            ArgError::message("--only, --kind, --errors and --match need --messages").into(),
+        );
+    }
+    if full_to.is_some() && limits.is_empty() {
+        return Err(ArgError::message(
+            "--full-to needs --head, --tail, --max-bytes or --max-binary",
+        )
+        .into());
+    }
+    if tui && tamper.debugger.is_some() {
+        return Err(ArgError::message("--tui and --step both need the terminal").into());
+    }
+    if use_tls && engine == Engine::Events {
+        return Err(ArgError::message("TLS is not supported by --engine=events").into());
+    }
+    match (chaos_action, &mut tamper.chaos) {
+        (Some(action), chaos) => chaos.get_or_insert_default().action = action,
+        (None, Some(_)) => {
+            return Err(ArgError::message("the --chaos-* options require --chaos").into())
+        }
+        (None, None) => {}
+    }
+    if let Some(ChaosRule {
+        side: Side::Client,
+        query: Some(_),
+        ..
+    }) = tamper.chaos
+    {
+        return Err(ArgError::message("--chaos-query only works with --chaos-side=server").into());
+    }
+    if !corrupt_modes.is_empty() {
+        tamper.corrupt = Some(Corruption {
+            modes: corrupt_modes,
+            probability: corrupt_probability,
+        });
+    }
+    match (inject_error, inject_match) {
+        (Some((sqlstate, message)), Some(matching)) => {
+            tamper.inject = Some(InjectRule {
+                matching,
+                sqlstate,
+                message,
+            })
+        }
+        (None, None) => {}
+        _ => {
+            let msg = "--inject-error and --inject-match must be used together";
+            return Err(ArgError::message(msg).into());
+        }
+    }
+    // Chaos does not know about messages that are held back
+    if let Some(chaos) = &tamper.chaos {
+        if tamper.debugger.is_some() {
+            return Err(ArgError::message("--chaos cannot be combined with --step").into());
+        }
+        if chaos.side == Side::Client && tamper.holds_queries() {
+            let msg = "--chaos-side=client cannot be combined with --inject-*, --rewrite, \
+                       --replace or the firewall options";
+            return Err(ArgError::message(msg).into());
+        }
+    }
+    tamper.upload = upload_rate.map(|rate| RateLimit::new(rate, shared_rate));
+    tamper.download = download_rate.map(|rate| RateLimit::new(rate, shared_rate));
+    if !tamper.is_empty() && engine == Engine::Events {
+        return Err(ArgError::message("tampering is not supported by --engine=events").into());
+    }
+    let tamper = Arc::new(tamper);
+
+    let tls = if use_tls {
+        Some(tls_settings.build()?)
+    } else {
+        None
+    };
+    let destination = Destination {
+        addr: forward_addr,
+        tls,
+    };
 
-    let mut formatter = TextFormatter::new(io::stdout());
+    // Before any threads are started
+    let signals = signals::watch()?;
+
+    let mut formatter = match &output {
+        Some(path) => TextFormatter::create(path)?,
+        None if tui => TextFormatter::new(io::sink()),
+        None => TextFormatter::new(io::stdout()),
+    };
     formatter.set_force_binary(force_binary);
+    formatter.set_limits(limits);
+    if let Some(path) = &full_to {
+        formatter
+            .save_full_to(path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+    }
+    if let Some(dir) = &split {
+        formatter
+            .split_to(dir)
+            .with_context(|| format!("could not write to {}", dir.display()))?;
+    }
+    if side_by_side {
+        let terminal_width = || crossterm::terminal::size().ok().map(|(w, _)| w as usize);
+        let width = width.or_else(|| output.is_none().then(terminal_width).flatten());
+        formatter.set_layout(Layout::columns(width.unwrap_or(DEFAULT_WIDTH)));
+    }
+    if let Some(capacity) = output_queue {
+        formatter.set_queue(capacity, when_behind)?;
+    }
 
     let formatter = Arc::new(Mutex::new(formatter));
+    let store = tui.then(|| Arc::new(Mutex::new(Store::bounded(LIVE_LIMIT))));
+    let recorder = match (&record, &store) {
+        (None, None) => None,
+        _ => {
+            let file = match &record {
+                Some(path) => Some(
+                    File::create(path)
+                        .with_context(|| format!("could not create {}", path.display()))?,
+                ),
+                None => None,
+            };
+            Some(Recorder::new(file, store.clone())?)
+        }
+    };
+    let connections = Connections::new();
+    connections.set_limit(max_connections);
 
-    for addr in expand_listen_address(&listen_addr)? {
-        let fw = forward_addr.clone();
-        let cloned = Arc::clone(&formatter);
-        match observe {
-            Observe::Raw => spawn_listener(addr, fw, cloned, RawObserver::new),
-            Observe::Blocks => spawn_listener(addr, fw, cloned, BlockObserver::new),
-            Observe::Messages => spawn_listener(addr, fw, cloned, MessageObserver::new),
+    let mut listeners = vec![];
+    for addr in listen_addr.expand() {
+        let addrs = match addr {
+            Address::Systemd(name) => activation.sockets(name.as_deref())?,
+            other => vec![other],
         };
+        for addr in addrs {
+            let listener = addr.listen(&unix_options)?;
+            eprintln!("Listening on {local}", local = listener.local);
+            let gate = connections.gate(max_per_listener, when_full);
+            listeners.push((listener, gate));
+        }
+    }
+
+    let conns = Arc::clone(&connections);
+    proxy::spawn_worker("timeouts", move || {
+        conns.enforce(timeouts);
+        Ok(())
+    });
+
+    let filter = Arc::new(filter);
+    let stoppers: Vec<_> = listeners.iter().map(|(l, _)| l.stopper.clone()).collect();
+    let mut listener_threads = vec![];
+    let mut event_loop = None;
+    match engine {
+        Engine::Threads => {
+            for (listener, gate) in listeners {
+                let fw = destination.clone();
+                let tp = Arc::clone(&tamper);
+                let conns = Arc::clone(&connections);
+                let cloned = Arc::clone(&formatter);
+                let rec = recorder.clone();
+                let filter = Arc::clone(&filter);
+                let filtered = move |side, f| MessageObserver::new(side, f, Arc::clone(&filter));
+                let thread = match observe {
+                    Observe::Raw => {
+                        spawn_listener(listener, gate, fw, tp, conns, cloned, rec, RawObserver::new)
+                    }
+                    Observe::Blocks => spawn_listener(
+                        listener,
+                        gate,
+                        fw,
+                        tp,
+                        conns,
+                        cloned,
+                        rec,
+                        BlockObserver::new,
+                    ),
+                    Observe::Messages => {
+                        spawn_listener(listener, gate, fw, tp, conns, cloned, rec, filtered)
+                    }
+                    Observe::PassThrough => spawn_listener(
+                        listener,
+                        gate,
+                        fw,
+                        tp,
+                        conns,
+                        cloned,
+                        rec,
+                        TransferObserver::new,
+                    ),
+                };
+                listener_threads.push(thread);
+            }
+        }
+        Engine::Events => {
+            let fw = destination;
+            let conns = Arc::clone(&connections);
+            let cloned = Arc::clone(&formatter);
+            let rec = recorder.clone();
+            let filtered = move |side, f| MessageObserver::new(side, f, Arc::clone(&filter));
+            let thread = match observe {
+                Observe::Raw => {
+                    spawn_event_loop(listeners, fw, conns, cloned, rec, RawObserver::new)
+                }
+                Observe::Blocks => {
+                    spawn_event_loop(listeners, fw, conns, cloned, rec, BlockObserver::new)
+                }
+                Observe::Messages => spawn_event_loop(listeners, fw, conns, cloned, rec, filtered),
+                Observe::PassThrough => {
+                    spawn_event_loop(listeners, fw, conns, cloned, rec, TransferObserver::new)
+                }
+            };
+            event_loop = Some(thread?);
+        }
+    }
+
+    if let Some(store) = store {
+        browser::run(store, || match signals.try_recv() {
+            Ok(Signal::Hangup) => {
+                reopen(&formatter);
+                false
+            }
+            Err(TryRecvError::Empty) => false,
+            Ok(_) | Err(TryRecvError::Disconnected) => true,
+        })?;
+    } else {
+        while let Signal::Hangup = signals.recv()? {
+            reopen(&formatter);
+        }
+    }
+
+    connections.begin_shutdown();
+    for stopper in stoppers {
+        stopper.stop();
+    }
+    shut_down(
+        &connections,
+        listener_threads,
+        event_loop,
+        &signals,
+        drain,
+        &formatter,
+    )?;
+    if let Some(recorder) = recorder {
+        recorder.flush()?;
+    }
+    Ok(())
+}
+
+fn shut_down(
+    connections: &Connections,
+    listener_threads: Vec<JoinHandle<()>>,
+    event_loop: Option<JoinHandle<()>>,
+    signals: &Receiver<Signal>,
+    drain: Duration,
+    formatter: &Mutex<TextFormatter>,
+) -> AResult<()> {
+    // This also removes the socket files
+    for thread in listener_threads {
+        let _ = thread.join();
+    }
+
+    let n = connections.active();
+    if n > 0 && !drain.is_zero() {
+        eprintln!(
+            "Waiting up to {secs}s for {n} connections to finish, interrupt again to close them now",
+            secs = drain.as_secs()
+        );
+        let deadline = Instant::now() + drain;
+        while connections.active() > 0 {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            match signals.recv_timeout(remaining.min(Duration::from_millis(100))) {
+                Ok(Signal::Hangup) => reopen(formatter),
+                Ok(Signal::Interrupt | Signal::Terminate) => break,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    connections.kill_all("proxy is shutting down");
+    // Give the connections a moment to report
+    connections.wait_idle(Duration::from_secs(1));
+    // The event loop also owns the listeners, so it has to be joined last
+    if let Some(thread) = event_loop {
+        let _ = thread.join();
+    }
+
+    let stats = connections.stats();
+    let summary = format!(
+        "shut down after {accepted} connections, {killed} closed by the proxy, {refused} refused, \
+         {from_client} bytes from clients, {from_server} bytes from servers",
+        accepted = stats.accepted,
+        killed = stats.killed,
+        refused = stats.refused,
+        from_client = stats.from_client,
+        from_server = stats.from_server,
+    );
+    let mut f = formatter.lock().unwrap();
+    f.stop_dropping();
+    f.proxy_message(&summary)?;
+    f.close()?;
+    Ok(())
+}
+
+fn reopen(formatter: &Mutex<TextFormatter>) {
+    if let Err(e) = formatter.lock().unwrap().reopen() {
+        eprintln!("Could not reopen output file: {e}");
+    }
+}
+
+fn parse_number(s: &str) -> Result<u64, ArgError> {
+    s.parse()
+        .map_err(|_| ArgError::message(format!("invalid number {s:?}")))
+}
+
+/// A number for which 0 makes no sense.
+fn parse_count(flag: &str, s: &str) -> Result<usize, ArgError> {
+    match parse_number(s)? {
+        0 => Err(ArgError::message(format!("{flag} must be at least 1"))),
+        n => Ok(n as usize),
+    }
+}
+
+fn parse_timeout(s: &str) -> Result<Duration, ArgError> {
+    match parse_number(s)? {
+        0 => Err(ArgError::message("timeouts must be at least 1 second")),
+        secs => Ok(Duration::from_secs(secs)),
     }
+}
+
+fn parse_engine(s: &str) -> Result<Engine, ArgError> {
+    match s {
+        "threads" => Ok(Engine::Threads),
+        "events" => Ok(Engine::Events),
+        _ => Err(ArgError::message(format!("invalid engine {s:?}"))),
+    }
+}
+
+fn parse_when_full(s: &str) -> Result<WhenFull, ArgError> {
+    match s {
+        "refuse" => Ok(WhenFull::Refuse),
+        "queue" => Ok(WhenFull::Queue),
+        _ => Err(ArgError::message(format!("invalid --when-full {s:?}"))),
+    }
+}
+
+fn parse_when_behind(s: &str) -> Result<WhenBehind, ArgError> {
+    match s {
+        "wait" => Ok(WhenBehind::Wait),
+        "drop" => Ok(WhenBehind::Drop),
+        _ => Err(ArgError::message(format!("invalid --when-behind {s:?}"))),
+    }
+}
+
+fn parse_millis(s: &str) -> Result<Duration, ArgError> {
+    Ok(Duration::from_millis(parse_number(s)?))
+}
 
-    loop {
-        thread::park()
+fn parse_rate(s: &str) -> Result<u64, ArgError> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k')) => (&s[..i], 1_000),
+        Some((i, 'M')) => (&s[..i], 1_000_000),
+        Some((i, 'G')) => (&s[..i], 1_000_000_000),
+        _ => (s, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(multiplier)
+            .ok_or_else(|| ArgError::message(format!("rate {s:?} is too large"))),
+        _ => Err(ArgError::message(format!("invalid rate {s:?}"))),
     }
 }
 
-fn expand_listen_address(listen_address: &Address) -> io::Result<Vec<Address>> {
-    let mut answers = vec![];
+fn parse_chaos_action(s: &str) -> Result<ChaosAction, ArgError> {
+    match s {
+        "close" => Ok(ChaosAction::Close),
+        "reset" => Ok(ChaosAction::Reset),
+        "truncate" => Ok(ChaosAction::Truncate),
+        "stall" => Ok(ChaosAction::Stall),
+        _ => Err(ArgError::message(format!("invalid chaos action {s:?}"))),
+    }
+}
 
-    for addr in listen_address.expand() {
-        if let Address::Inet(inet) = addr {
-            for a in inet.to_socket_addrs()? {
-                answers.push(Address::Inet(a.to_string()))
+fn parse_corrupt_modes(s: &str) -> Result<Vec<CorruptMode>, ArgError> {
+    let mut modes = vec![];
+    for mode in s.split(',') {
+        let mode = match mode {
+            "length" => CorruptMode::Length,
+            "no-last" => CorruptMode::NoLast,
+            "bitflip" => CorruptMode::BitFlip,
+            "no-prompt" => CorruptMode::NoPrompt,
+            _ => {
+                return Err(ArgError::message(format!(
+                    "invalid corruption mode {mode:?}"
+                )))
             }
-        } else {
-            answers.push(addr)
+        };
+        modes.push(mode);
+    }
+    Ok(modes)
+}
+
+fn parse_kinds(s: &str) -> Result<Vec<&'static str>, ArgError> {
+    let mut kinds = vec![];
+    for kind in s.split(',') {
+        match filter::KINDS.iter().find(|k| **k == kind) {
+            Some(k) => kinds.push(*k),
+            None => return Err(ArgError::message(format!("invalid message kind {kind:?}"))),
         }
     }
+    Ok(kinds)
+}
+
+fn parse_error(s: &str) -> Result<(String, String), ArgError> {
+    match s.split_once('!') {
+        Some((sqlstate, message))
+            if sqlstate.len() == 5
+                && sqlstate.bytes().all(|b| b.is_ascii_alphanumeric())
+                && !message.contains('\n') =>
+        {
+            Ok((sqlstate.to_string(), message.to_string()))
+        }
+        _ => Err(ArgError::message(format!(
+            "invalid error {s:?}, expected SQLSTATE!MESSAGE"
+        ))),
+    }
+}
+
+fn parse_substitution(s: &str) -> Result<(String, String), ArgError> {
+    match s.split_once("=>") {
+        Some((from, to)) if !from.is_empty() => Ok((from.to_string(), to.to_string())),
+        _ => Err(ArgError::message(format!(
+            "invalid substitution {s:?}, expected FROM=>TO"
+        ))),
+    }
+}
+
+fn parse_probability(s: &str) -> Result<f64, ArgError> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(ArgError::message(format!("invalid probability {s:?}"))),
+    }
+}
+
+fn parse_side(s: &str) -> Result<Side, ArgError> {
+    match s {
+        "client" => Ok(Side::Client),
+        "server" => Ok(Side::Server),
+        _ => Err(ArgError::message(format!("invalid side {s:?}"))),
+    }
+}
+
+fn parse_regex(s: &str) -> Result<Regex, ArgError> {
+    Regex::new(s).map_err(|e| ArgError::message(format!("invalid regex {s:?}: {e}")))
+}
 
-    Ok(answers)
+fn parse_mode(s: &str) -> Result<u32, ArgError> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(ArgError::message(format!("invalid socket mode {s:?}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_count() {
+        assert_eq!(parse_count("--width", "80").ok(), Some(80));
+        let error = parse_count("--width", "0").unwrap_err();
+        assert_eq!(error.to_string(), "--width must be at least 1");
+        assert!(parse_count("--width", "-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("100").ok(), Some(100));
+        assert_eq!(parse_rate("5k").ok(), Some(5_000));
+        assert_eq!(parse_rate("2M").ok(), Some(2_000_000));
+        assert_eq!(parse_rate("3G").ok(), Some(3_000_000_000));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("k").is_err());
+        assert!(parse_rate("1.5M").is_err());
+        assert!(parse_rate("99999999999999999G").is_err());
+        assert_eq!(parse_rate("18446744073709551615").ok(), Some(u64::MAX));
+    }
 }
@@ -1,113 +1,776 @@
 use std::borrow::Cow;
-use std::ffi::OsStr;
+use std::ffi::{CString, OsStr};
 use std::io::{Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
-use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::{self, PathBuf};
-use std::{fmt, fs, io};
+use std::net::ToSocketAddrs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{chown, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{SocketAddr as UnixSocketAddr, UnixListener, UnixStream};
+use std::path::{self, Path, PathBuf};
+use std::sync::Arc;
+use std::{env, fmt, fs, io, mem, process, ptr};
+
+use socket2::{Domain, SockRef, Socket, Type};
+
+use crate::tls::{TlsReader, TlsWriter};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Address {
-    Inet(String),
+    Inet(InetAddr),
     Unix(PathBuf),
+    /// Linux abstract socket namespace, written as `@name`
+    Abstract(Vec<u8>),
     PortOnly(u16),
+    /// Listening socket inherited from our parent
+    Fd(RawFd),
+    /// Listening sockets passed by systemd, optionally only those with
+    /// the given name
+    Systemd(Option<String>),
+}
+
+/// Address families a TCP address may resolve to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Family {
+    Any,
+    V4,
+    V6,
 }
 
-pub type Accepter = dyn FnMut() -> io::Result<(Incoming, Outgoing, Address)>;
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Host {
+    Name(String),
+    Ip(IpAddr),
+    /// All interfaces, written as `*`
+    Wildcard,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InetAddr {
+    pub family: Family,
+    pub host: Host,
+    pub port: u16,
+}
+
+pub type Accepter = dyn FnMut() -> io::Result<(Incoming, Outgoing, Address)> + Send;
+
+/// A bound listening socket
+pub struct Listener {
+    pub accepter: Box<Accepter>,
+    /// The address actually bound, differs from the requested address
+    /// if a host name had to be resolved
+    pub local: Address,
+    pub stopper: Stopper,
+}
+
+impl Listener {
+    /// Make the accepter return [`io::ErrorKind::WouldBlock`] instead of
+    /// waiting for a client.
+    pub fn set_nonblocking(&self) -> io::Result<()> {
+        // The descriptors share their flags so it doesn't matter we
+        // use the duplicate
+        set_nonblocking(self.as_raw_fd())
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stopper.0.as_raw_fd()
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fcntl F_GETFL and F_SETFL do not touch memory
+    let rc = unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 {
+            flags
+        } else {
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK)
+        }
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes a blocked accept() on the listening socket return with an error.
+/// Holds its own duplicate of the file descriptor so it remains safe to use
+/// after the listener has been dropped.
+#[derive(Clone)]
+pub struct Stopper(Arc<OwnedFd>);
+
+impl Stopper {
+    fn new(fd: impl AsFd) -> io::Result<Stopper> {
+        Ok(Stopper(Arc::new(fd.as_fd().try_clone_to_owned()?)))
+    }
+
+    pub fn stop(&self) {
+        // SAFETY: the descriptor is owned by us and therefore valid
+        unsafe { libc::shutdown(self.0.as_raw_fd(), libc::SHUT_RDWR) };
+    }
+}
 
 impl Address {
+    /// Parse an address as given on the command line.
+    ///
+    /// ```plain
+    /// PORT                    both unix:/tmp/.s.monetdb.PORT and tcp:localhost:PORT
+    /// [SCHEME:]HOST:PORT      HOST is a host name, an IPv4 address,
+    ///                         a bracketed IPv6 address or * for all interfaces
+    /// SCHEME:PORT             same as SCHEME:localhost:PORT
+    /// unix:PATH               also PATH if it contains a slash
+    /// @NAME                   abstract unix socket, Linux only, also unix:@NAME
+    /// fd:N                    inherited listening socket
+    /// systemd[:NAME]          sockets passed by systemd socket activation
+    /// ```
+    ///
+    /// SCHEME is `tcp`, or `tcp4` or `tcp6` to restrict the address family.
+    /// Host names are not resolved until the address is used.
     pub fn parse(s: impl AsRef<OsStr>) -> io::Result<Address> {
         let s = s.as_ref();
 
+        let bytes = s.as_bytes();
+        let unix_path = bytes.strip_prefix(b"unix:");
+        if let Some(name) = unix_path.unwrap_or(bytes).strip_prefix(b"@") {
+            if name.is_empty() {
+                return Err(invalid_address(s, "missing abstract socket name"));
+            }
+            return Ok(Address::Abstract(name.to_vec()));
+        }
+        if let Some(path) = unix_path {
+            if path.is_empty() {
+                return Err(invalid_address(s, "missing socket path"));
+            }
+            return Ok(Address::Unix(OsStr::from_bytes(path).into()));
+        }
+
         let lossy = OsStr::to_string_lossy(s);
+        if lossy == "systemd" {
+            return Ok(Address::Systemd(None));
+        }
+        if let Some(name) = lossy.strip_prefix("systemd:") {
+            return Ok(Address::Systemd(Some(name.to_string())));
+        }
+        if let Some(fd) = lossy.strip_prefix("fd:") {
+            return match fd.parse() {
+                Ok(fd) if fd >= 0 => Ok(Address::Fd(fd)),
+                _ => Err(invalid_address(s, "invalid file descriptor")),
+            };
+        }
+
         if lossy.contains(['/', path::MAIN_SEPARATOR]) {
             return Ok(Address::Unix(s.into()));
         }
 
-        if let Cow::Borrowed(t) = lossy {
-            // It was valid UTF-8
+        let Cow::Borrowed(t) = lossy else {
+            return Err(invalid_address(s, "not valid UTF-8"));
+        };
 
-            if t.to_socket_addrs().is_ok() {
-                return Ok(Address::Inet(t.to_string()));
-            }
+        let (family, rest) = match t.split_once(':') {
+            Some(("tcp", rest)) => (Some(Family::Any), rest),
+            Some(("tcp4", rest)) => (Some(Family::V4), rest),
+            Some(("tcp6", rest)) => (Some(Family::V6), rest),
+            _ => (None, t),
+        };
 
-            if let Ok(n) = t.parse() {
-                return Ok(Address::PortOnly(n));
-            }
+        if family.is_none() && is_port_number(rest) {
+            let port = parse_port(rest).map_err(|e| invalid_address(s, e))?;
+            return Ok(Address::PortOnly(port));
         }
 
-        let kind: io::ErrorKind = io::ErrorKind::NotFound;
-        Err(io::Error::new(kind, format!("invalid address: {s:?}")))
+        let family = family.unwrap_or(Family::Any);
+        match parse_inet(family, rest) {
+            Ok(inet) => Ok(Address::Inet(inet)),
+            Err(e) => Err(invalid_address(s, e)),
+        }
     }
 
     pub fn to_inet(&self) -> Option<Address> {
         match self {
             Address::Inet(_) => Some(self.clone()),
-            Address::Unix(_) => None,
-            Address::PortOnly(n) => Some(Address::Inet(format!("localhost:{n}"))),
+            Address::Unix(_) | Address::Abstract(_) => None,
+            Address::Fd(_) | Address::Systemd(_) => None,
+            Address::PortOnly(n) => Some(Address::Inet(InetAddr {
+                family: Family::Any,
+                host: Host::Name("localhost".to_string()),
+                port: *n,
+            })),
         }
     }
 
     pub fn to_unix(&self) -> Option<Address> {
         match self {
             Address::Inet(_) => None,
-            Address::Unix(_) => Some(self.clone()),
+            Address::Fd(_) | Address::Systemd(_) => None,
+            Address::Unix(_) | Address::Abstract(_) => Some(self.clone()),
             Address::PortOnly(n) => Some(Address::Unix(format!("/tmp/.s.monetdb.{n}").into())),
         }
     }
 
     pub fn expand(&self) -> impl Iterator<Item = Address> {
-        self.to_unix().into_iter().chain(self.to_inet())
+        let inherited = matches!(self, Address::Fd(_) | Address::Systemd(_)).then(|| self.clone());
+        self.to_unix()
+            .into_iter()
+            .chain(self.to_inet())
+            .chain(inherited)
     }
 
-    pub fn listen(&self) -> io::Result<Box<Accepter>> {
+    pub fn listen(&self, options: &UnixOptions) -> io::Result<Listener> {
         match self {
             Address::PortOnly(_) => panic!("cannot invoke listen() on PortOnly"),
-            Address::Inet(a) => {
-                let listener = TcpListener::bind(a)?;
-                Ok(Box::new(move || {
-                    let (conn, client) = listener.accept()?;
-                    let from_client = Incoming::Inet(conn.try_clone()?);
-                    let to_client = Outgoing::Inet(conn);
-                    let client = Address::Inet(client.to_string());
-                    Ok((from_client, to_client, client))
-                }))
-            }
+            Address::Systemd(_) => panic!("cannot invoke listen() on Systemd"),
+            Address::Inet(a) => inet_listener(a.bind()?),
             Address::Unix(p) => {
+                // Only we can connect until the options have been applied
+                let umask = (!options.is_empty()).then(|| Umask::set(0o177));
                 let listener = match UnixListener::bind(p) {
                     Ok(l) => l,
                     Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
-                        fs::remove_file(p)?;
+                        remove_stale_socket(p)?;
                         UnixListener::bind(p)?
                     }
                     Err(other) => return Err(other),
                 };
-                let p = p.clone();
-                Ok(Box::new(move || {
-                    let (conn, _) = listener.accept()?;
-                    let from_client = Incoming::Unix(conn.try_clone()?);
-                    let to_client = Outgoing::Unix(conn);
-                    let client = Address::Unix(p.clone());
-                    Ok((from_client, to_client, client))
-                }))
+                let socket_file = SocketFile::new(p)?;
+                if let Some(umask) = umask {
+                    let original = umask.0;
+                    drop(umask);
+                    options.apply(p, original)?;
+                }
+                unix_listener(listener, self.clone(), Some(socket_file))
+            }
+            Address::Abstract(name) => {
+                // Abstract sockets vanish with the last file descriptor,
+                // there is never a stale one to remove
+                let listener = UnixListener::bind_addr(&abstract_socket_addr(name)?)?;
+                unix_listener(listener, self.clone(), None)
+            }
+            Address::Fd(fd) => inherited_listener(*fd),
+        }
+    }
+
+    /// Connect to a Unix domain socket, either a path or an abstract name.
+    pub fn connect_unix(&self) -> io::Result<UnixStream> {
+        match self {
+            Address::Unix(p) => UnixStream::connect(p),
+            Address::Abstract(name) => UnixStream::connect_addr(&abstract_socket_addr(name)?),
+            _ => panic!("connect_unix() invoked on non-unix address {self}"),
+        }
+    }
+}
+
+fn inet_listener(listener: TcpListener) -> io::Result<Listener> {
+    let local = Address::Inet(listener.local_addr()?.into());
+    let stopper = Stopper::new(&listener)?;
+    let accepter = Box::new(move || {
+        let (conn, client) = listener.accept()?;
+        let from_client = Incoming::Inet(conn.try_clone()?);
+        let to_client = Outgoing::Inet(conn);
+        let client = Address::Inet(client.into());
+        Ok((from_client, to_client, client))
+    });
+    Ok(Listener {
+        accepter,
+        local,
+        stopper,
+    })
+}
+
+fn unix_listener(
+    listener: UnixListener,
+    local: Address,
+    socket_file: Option<SocketFile>,
+) -> io::Result<Listener> {
+    let stopper = Stopper::new(&listener)?;
+    let client = local.clone();
+    let accepter = Box::new(move || {
+        // The socket file must live exactly as long as the accepter
+        let _ = &socket_file;
+        let (conn, _) = listener.accept()?;
+        let from_client = Incoming::Unix(conn.try_clone()?);
+        let to_client = Outgoing::Unix(conn);
+        Ok((from_client, to_client, client.clone()))
+    });
+    Ok(Listener {
+        accepter,
+        local,
+        stopper,
+    })
+}
+
+/// Take over a listening socket passed to us by our parent process,
+/// for example systemd.
+fn inherited_listener(fd: RawFd) -> io::Result<Listener> {
+    let fd_error = |msg: &str| {
+        let kind = io::ErrorKind::InvalidInput;
+        io::Error::new(kind, format!("fd:{fd}: {msg}"))
+    };
+
+    // SAFETY: F_GETFD only inspects the descriptor table
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(fd_error("not an open file descriptor"));
+    }
+    // Only take ownership once we know it's ours to use, otherwise
+    // something like fd:1 would be closed
+    {
+        // SAFETY: the descriptor is open and we only look at it
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        let socket = SockRef::from(&borrowed);
+        let is_stream = socket.r#type().is_ok_and(|t| t == Type::STREAM);
+        if !is_stream || !socket.is_listener().unwrap_or(false) {
+            return Err(fd_error("not a listening stream socket"));
+        }
+    }
+    // SAFETY: the descriptor is a listening socket handed to us, so it's ours
+    let socket = Socket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    socket.set_cloexec(true)?;
+
+    let local = socket.local_addr()?;
+    if local.is_unix() {
+        let listener = UnixListener::from(OwnedFd::from(socket));
+        let local = unix_address(&listener.local_addr()?).unwrap_or(Address::Fd(fd));
+        unix_listener(listener, local, None)
+    } else if local.as_socket().is_some() {
+        inet_listener(TcpListener::from(OwnedFd::from(socket)))
+    } else {
+        Err(fd_error("unsupported address family"))
+    }
+}
+
+fn unix_address(addr: &UnixSocketAddr) -> Option<Address> {
+    if let Some(path) = addr.as_pathname() {
+        return Some(Address::Unix(path.to_owned()));
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::linux::net::SocketAddrExt;
+        if let Some(name) = addr.as_abstract_name() {
+            return Some(Address::Abstract(name.to_vec()));
+        }
+    }
+    None
+}
+
+/// The sockets passed to us through systemd socket activation, see
+/// sd_listen_fds(3), with their FileDescriptorName. Or why there are none.
+#[derive(Debug)]
+pub struct Activation(Result<Vec<(RawFd, String)>, &'static str>);
+
+impl Activation {
+    /// Read the LISTEN_* variables and remove them from the environment.
+    /// Changing the environment is only sound while no other threads are
+    /// running, so this must be called before any are started.
+    pub fn take() -> Activation {
+        let Ok(pid) = env::var("LISTEN_PID") else {
+            return Activation(Err("LISTEN_PID not set"));
+        };
+        if pid.parse() != Ok(process::id()) {
+            return Activation(Err("LISTEN_PID is not our pid"));
+        }
+        let count = env::var("LISTEN_FDS").ok();
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+        // Like sd_listen_fds(1), so our children won't try to use them
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        let Some(count) = count else {
+            return Activation(Err("LISTEN_FDS not set"));
+        };
+        Activation(Activation::parse(&count, &names))
+    }
+
+    /// The sockets given the values of LISTEN_FDS and LISTEN_FDNAMES.
+    fn parse(count: &str, names: &str) -> Result<Vec<(RawFd, String)>, &'static str> {
+        const SD_LISTEN_FDS_START: RawFd = 3;
+
+        let end = count
+            .parse::<RawFd>()
+            .ok()
+            .filter(|&n| n >= 0)
+            .and_then(|n| SD_LISTEN_FDS_START.checked_add(n))
+            .ok_or("LISTEN_FDS invalid or out of range")?;
+        let mut names = names.split(':');
+        let sockets = (SD_LISTEN_FDS_START..end)
+            .map(|fd| (fd, names.next().unwrap_or("unknown").to_string()))
+            .collect();
+        Ok(sockets)
+    }
+
+    /// If a name is given, only the sockets with that FileDescriptorName.
+    pub fn sockets(&self, name: Option<&str>) -> io::Result<Vec<Address>> {
+        let not_found = |msg: &str| {
+            let kind = io::ErrorKind::NotFound;
+            io::Error::new(kind, format!("no sockets from systemd: {msg}"))
+        };
+        let all = self.0.as_ref().map_err(|msg| not_found(msg))?;
+        let sockets: Vec<_> = all
+            .iter()
+            .filter(|(_, fd_name)| name.is_none_or(|n| n == fd_name))
+            .map(|(fd, _)| Address::Fd(*fd))
+            .collect();
+        if sockets.is_empty() {
+            return Err(not_found(&format!("none named {:?}", name.unwrap_or(""))));
+        }
+        Ok(sockets)
+    }
+}
+
+/// Called when binding to `p` failed because the file already exists.
+/// Only remove it if it's a socket nobody is listening on anymore.
+fn remove_stale_socket(p: &Path) -> io::Result<()> {
+    let in_use = |msg: &str| {
+        let kind = io::ErrorKind::AddrInUse;
+        Err(io::Error::new(kind, format!("{}: {msg}", p.display())))
+    };
+
+    if !fs::symlink_metadata(p)?.file_type().is_socket() {
+        return in_use("file exists and is not a socket");
+    }
+    match UnixStream::connect(p) {
+        Ok(_) => in_use("another process is listening on this socket"),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(p),
+        Err(e) => Err(e),
+    }
+}
+
+/// Permissions and ownership to apply to the socket files we create.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnixOptions {
+    pub mode: Option<u32>,
+    pub group: Option<u32>,
+}
+
+impl UnixOptions {
+    fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.group.is_none()
+    }
+
+    /// Without a mode, the socket gets the one `umask` would have given it.
+    fn apply(&self, p: &Path, umask: libc::mode_t) -> io::Result<()> {
+        if let Some(group) = self.group {
+            chown(p, None, Some(group))?;
+        }
+        // mode_t is u16 on some systems
+        #[allow(clippy::unnecessary_cast)]
+        let mode = self.mode.unwrap_or(0o777 & !(umask as u32));
+        fs::set_permissions(p, fs::Permissions::from_mode(mode))
+    }
+}
+
+/// Changes the umask until dropped. The umask is shared by all threads, so
+/// this is only done while starting up, when no other thread creates files.
+struct Umask(libc::mode_t);
+
+impl Umask {
+    fn set(mask: libc::mode_t) -> Umask {
+        // SAFETY: umask(2) cannot fail
+        Umask(unsafe { libc::umask(mask) })
+    }
+}
+
+impl Drop for Umask {
+    fn drop(&mut self) {
+        // SAFETY: see above
+        unsafe { libc::umask(self.0) };
+    }
+}
+
+/// Look up a group by name or number.
+pub fn lookup_group(name: &str) -> io::Result<u32> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+
+    let cname = CString::new(name)?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: all pointers point to live, properly sized data
+        let mut group: libc::group = unsafe { mem::zeroed() };
+        let mut result: *mut libc::group = ptr::null_mut();
+        let rc = unsafe {
+            libc::getgrnam_r(
+                cname.as_ptr(),
+                &mut group,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match rc {
+            0 if result.is_null() => {
+                let kind = io::ErrorKind::NotFound;
+                return Err(io::Error::new(kind, format!("no such group: {name}")));
+            }
+            0 => return Ok(group.gr_gid),
+            libc::ERANGE => buf.resize(2 * buf.len(), 0),
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+/// A socket file created by us. It is removed again when the listener is
+/// dropped, unless someone else has replaced it in the meantime.
+struct SocketFile {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+}
+
+impl SocketFile {
+    fn new(path: &Path) -> io::Result<SocketFile> {
+        let meta = fs::symlink_metadata(path)?;
+        Ok(SocketFile {
+            path: path.to_owned(),
+            dev: meta.dev(),
+            ino: meta.ino(),
+        })
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Ok(meta) = fs::symlink_metadata(&self.path) {
+            if meta.dev() == self.dev && meta.ino() == self.ino {
+                let _ = fs::remove_file(&self.path);
             }
         }
     }
 }
 
+#[cfg(target_os = "linux")]
+fn abstract_socket_addr(name: &[u8]) -> io::Result<UnixSocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    UnixSocketAddr::from_abstract_name(name)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_socket_addr(_name: &[u8]) -> io::Result<UnixSocketAddr> {
+    let kind = io::ErrorKind::Unsupported;
+    Err(io::Error::new(
+        kind,
+        "abstract unix sockets are only supported on Linux",
+    ))
+}
+
+fn parse_inet(family: Family, s: &str) -> Result<InetAddr, String> {
+    let (host, port) = if is_port_number(s) {
+        (Host::Name("localhost".to_string()), s)
+    } else if let Some(bracketed) = s.strip_prefix('[') {
+        let Some((ip, rest)) = bracketed.split_once(']') else {
+            return Err("missing ']' after IPv6 address".to_string());
+        };
+        let Ok(ip) = ip.parse::<Ipv6Addr>() else {
+            return Err(format!("{ip:?} is not a valid IPv6 address"));
+        };
+        let Some(port) = rest.strip_prefix(':') else {
+            return Err("expected ':PORT' after ']'".to_string());
+        };
+        (Host::Ip(ip.into()), port)
+    } else {
+        let Some((host, port)) = s.rsplit_once(':') else {
+            return Err("missing port number".to_string());
+        };
+        if host.contains(':') {
+            let msg = "IPv6 addresses must be enclosed in brackets, for example [::1]:50000";
+            return Err(msg.to_string());
+        }
+        (parse_host(host)?, port)
+    };
+    let port = parse_port(port)?;
+
+    match (&host, family) {
+        (Host::Ip(IpAddr::V4(ip)), Family::V6) => Err(format!("{ip} is not an IPv6 address")),
+        (Host::Ip(IpAddr::V6(ip)), Family::V4) => Err(format!("{ip} is not an IPv4 address")),
+        _ => Ok(InetAddr { family, host, port }),
+    }
+}
+
+fn parse_host(host: &str) -> Result<Host, String> {
+    if host == "*" {
+        return Ok(Host::Wildcard);
+    }
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        return Ok(Host::Ip(ip.into()));
+    }
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_';
+    if host.is_empty() {
+        Err("missing host name".to_string())
+    } else if !host.chars().all(valid) {
+        Err(format!("invalid host name {host:?}"))
+    } else {
+        Ok(Host::Name(host.to_string()))
+    }
+}
+
+fn is_port_number(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn parse_port(s: &str) -> Result<u16, String> {
+    match s.parse() {
+        Ok(n) => Ok(n),
+        Err(_) if is_port_number(s) => Err(format!("port number {s} out of range")),
+        Err(_) => Err(format!("invalid port number {s:?}")),
+    }
+}
+
+fn invalid_address(s: &OsStr, msg: impl fmt::Display) -> io::Error {
+    let kind = io::ErrorKind::InvalidInput;
+    io::Error::new(kind, format!("invalid address {s:?}: {msg}"))
+}
+
+impl InetAddr {
+    /// Look up the socket addresses this address refers to, restricted
+    /// to the requested family.
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let candidates: Vec<SocketAddr> = match &self.host {
+            Host::Name(name) => (name.as_str(), self.port).to_socket_addrs()?.collect(),
+            Host::Ip(ip) => vec![SocketAddr::new(*ip, self.port)],
+            Host::Wildcard if self.family == Family::V4 => {
+                vec![SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.port)]
+            }
+            // For Family::Any, this will be a dual-stack socket
+            Host::Wildcard => vec![SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), self.port)],
+        };
+        let found: Vec<SocketAddr> = candidates
+            .into_iter()
+            .filter(|a| self.family.admits(a))
+            .collect();
+        if found.is_empty() {
+            let kind = io::ErrorKind::NotFound;
+            let msg = format!(
+                "{host} has no {family} address",
+                host = self.host,
+                family = self.family
+            );
+            return Err(io::Error::new(kind, msg));
+        }
+        Ok(found)
+    }
+
+    /// Bind to the first address `self` resolves to.
+    pub fn bind(&self) -> io::Result<TcpListener> {
+        let addr = self.resolve()?[0];
+        match bind_tcp(addr, self.family == Family::V6) {
+            Err(e) if self.host == Host::Wildcard && self.family == Family::Any => {
+                // No IPv6 on this host?
+                let fallback = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.port);
+                bind_tcp(fallback, false).map_err(|_| e)
+            }
+            other => other,
+        }
+    }
+
+    /// Connect to the first of the resolved addresses that accepts the connection.
+    pub fn connect(&self) -> io::Result<TcpStream> {
+        TcpStream::connect(&self.resolve()?[..])
+    }
+
+    /// Start connecting to the first resolved address without waiting for
+    /// the connection to be established. Once the socket becomes writable,
+    /// [`TcpStream::take_error`] tells whether it succeeded.
+    pub fn start_connect(&self) -> io::Result<TcpStream> {
+        let addr = self.resolve()?[0];
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+        match socket.connect(&addr.into()) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(socket.into())
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    // Same as std's TcpListener::bind
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+impl Family {
+    fn admits(self, addr: &SocketAddr) -> bool {
+        match self {
+            Family::Any => true,
+            Family::V4 => addr.is_ipv4(),
+            Family::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl From<SocketAddr> for InetAddr {
+    fn from(addr: SocketAddr) -> Self {
+        InetAddr {
+            family: Family::Any,
+            host: Host::Ip(addr.ip()),
+            port: addr.port(),
+        }
+    }
+}
+
 impl fmt::Display for Address {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Address::Inet(a) => a.fmt(f),
             Address::Unix(a) => a.display().fmt(f),
+            Address::Abstract(name) => write!(f, "@{}", String::from_utf8_lossy(name)),
             Address::PortOnly(a) => a.fmt(f),
+            Address::Fd(fd) => write!(f, "fd:{fd}"),
+            Address::Systemd(None) => f.write_str("systemd"),
+            Address::Systemd(Some(name)) => write!(f, "systemd:{name}"),
         }
     }
 }
 
+impl fmt::Display for InetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.family {
+            Family::Any => {}
+            Family::V4 => f.write_str("tcp4:")?,
+            Family::V6 => f.write_str("tcp6:")?,
+        }
+        match &self.host {
+            Host::Ip(IpAddr::V6(ip)) => write!(f, "[{ip}]:{port}", port = self.port),
+            host => write!(f, "{host}:{port}", port = self.port),
+        }
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Name(name) => name.fmt(f),
+            Host::Ip(ip) => ip.fmt(f),
+            Host::Wildcard => f.write_str("*"),
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Family::Any => "IP",
+            Family::V4 => "IPv4",
+            Family::V6 => "IPv6",
+        };
+        f.write_str(s)
+    }
+}
+
 pub enum Incoming {
     Inet(TcpStream),
     Unix(UnixStream),
+    Tls(TlsReader),
 }
 
 impl Read for Incoming {
@@ -115,15 +778,42 @@ impl Read for Incoming {
         match self {
             Incoming::Inet(conn) => conn.read(buf),
             Incoming::Unix(conn) => conn.read(buf),
+            Incoming::Tls(conn) => conn.read(buf),
+        }
+    }
+}
+
+impl AsRawFd for Incoming {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Incoming::Inet(conn) => conn.as_raw_fd(),
+            Incoming::Unix(conn) => conn.as_raw_fd(),
+            Incoming::Tls(conn) => conn.socket().as_raw_fd(),
         }
     }
 }
 
 impl Incoming {
+    /// Also affects the [`Outgoing`] it was cloned from.
+    pub fn set_nonblocking(&self) -> io::Result<()> {
+        set_nonblocking(self.as_raw_fd())
+    }
+
+    /// The socket, if the data can be moved without looking at it,
+    /// that is, if it's not TLS.
+    pub fn plain_fd(&self) -> Option<RawFd> {
+        match self {
+            Incoming::Inet(conn) => Some(conn.as_raw_fd()),
+            Incoming::Unix(conn) => Some(conn.as_raw_fd()),
+            Incoming::Tls(_) => None,
+        }
+    }
+
     pub fn shutdown(&mut self) -> io::Result<()> {
         match self {
             Incoming::Inet(conn) => conn.shutdown(Shutdown::Read),
             Incoming::Unix(conn) => conn.shutdown(Shutdown::Read),
+            Incoming::Tls(conn) => conn.shutdown(),
         }
     }
 }
@@ -131,6 +821,7 @@ impl Incoming {
 pub enum Outgoing {
     Inet(TcpStream),
     Unix(UnixStream),
+    Tls(TlsWriter),
 }
 
 impl Write for Outgoing {
@@ -138,6 +829,7 @@ impl Write for Outgoing {
         match self {
             Outgoing::Inet(conn) => conn.write(buf),
             Outgoing::Unix(conn) => conn.write(buf),
+            Outgoing::Tls(conn) => conn.write(buf),
         }
     }
 
@@ -145,15 +837,183 @@ impl Write for Outgoing {
         match self {
             Outgoing::Inet(conn) => conn.flush(),
             Outgoing::Unix(conn) => conn.flush(),
+            Outgoing::Tls(conn) => conn.flush(),
         }
     }
 }
 
 impl Outgoing {
+    /// See [`Incoming::plain_fd`].
+    pub fn plain_fd(&self) -> Option<RawFd> {
+        match self {
+            Outgoing::Inet(conn) => Some(conn.as_raw_fd()),
+            Outgoing::Unix(conn) => Some(conn.as_raw_fd()),
+            Outgoing::Tls(_) => None,
+        }
+    }
+
     pub fn shutdown(&mut self) -> io::Result<()> {
         match self {
             Outgoing::Inet(conn) => conn.shutdown(Shutdown::Write),
             Outgoing::Unix(conn) => conn.shutdown(Shutdown::Write),
+            Outgoing::Tls(conn) => conn.shutdown(),
+        }
+    }
+}
+
+/// Handle to forcibly close a connection from another thread.
+pub enum Closer {
+    Inet(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Closer {
+    pub fn close(&self) {
+        let _ = match self {
+            Closer::Inet(conn) => conn.shutdown(Shutdown::Both),
+            Closer::Unix(conn) => conn.shutdown(Shutdown::Both),
+        };
+    }
+
+    /// Make a thread waiting to read from the connection see the end of
+    /// the stream, leaving the other direction alone.
+    pub fn stop_reading(&self) {
+        let _ = match self {
+            Closer::Inet(conn) => conn.shutdown(Shutdown::Read),
+            Closer::Unix(conn) => conn.shutdown(Shutdown::Read),
+        };
+    }
+
+    /// Abort the connection. For TCP the peer receives a RST,
+    /// Unix domain sockets are simply closed.
+    pub fn reset(&self) {
+        match self {
+            Closer::Inet(conn) => {
+                // Connecting a TCP socket to AF_UNSPEC disconnects it, sending
+                // a RST. Unlike close() this works while other threads use it.
+                // SAFETY: an all zero sockaddr is AF_UNSPEC
+                unsafe {
+                    let addr: libc::sockaddr = mem::zeroed();
+                    let len = mem::size_of_val(&addr) as libc::socklen_t;
+                    libc::connect(conn.as_raw_fd(), &addr, len);
+                }
+                // Wake up the threads still reading and writing
+                let _ = conn.shutdown(Shutdown::Both);
+            }
+            Closer::Unix(_) => self.close(),
+        }
+    }
+}
+
+impl Incoming {
+    pub fn closer(&self) -> io::Result<Closer> {
+        match self {
+            Incoming::Inet(conn) => Ok(Closer::Inet(conn.try_clone()?)),
+            Incoming::Unix(conn) => Ok(Closer::Unix(conn.try_clone()?)),
+            Incoming::Tls(conn) => Ok(Closer::Inet(conn.socket().try_clone()?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inet(family: Family, host: Host, port: u16) -> Address {
+        Address::Inet(InetAddr { family, host, port })
+    }
+
+    fn name(host: &str) -> Host {
+        Host::Name(host.to_string())
+    }
+
+    fn error(s: &str) -> String {
+        Address::parse(s).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_parse_tcp() {
+        let parse = |s| Address::parse(s).unwrap();
+        assert_eq!(parse("50000"), Address::PortOnly(50000));
+        assert_eq!(parse("db:50000"), inet(Family::Any, name("db"), 50000));
+        assert_eq!(
+            parse("tcp:50000"),
+            inet(Family::Any, name("localhost"), 50000)
+        );
+        assert_eq!(
+            parse("tcp4:127.0.0.1:1"),
+            inet(Family::V4, Host::Ip([127, 0, 0, 1].into()), 1)
+        );
+        assert_eq!(
+            parse("[::1]:50000"),
+            inet(Family::Any, Host::Ip(Ipv6Addr::LOCALHOST.into()), 50000)
+        );
+        assert_eq!(parse("tcp6:*:2"), inet(Family::V6, Host::Wildcard, 2));
+
+        assert!(error("::1:50000").contains("must be enclosed in brackets"));
+        assert!(error("[::1]").contains("expected ':PORT'"));
+        assert!(error("tcp6:127.0.0.1:1").contains("not an IPv6 address"));
+        assert!(error("tcp4:[::1]:1").contains("not an IPv4 address"));
+        assert!(error("db:70000").contains("out of range"));
+        assert!(error("db:x").contains("invalid port number"));
+        assert!(error("d b:1").contains("invalid host name"));
+        assert!(error("db").contains("missing port number"));
+    }
+
+    #[test]
+    fn test_parse_unix() {
+        let parse = |s| Address::parse(s).unwrap();
+        assert_eq!(parse("/tmp/sock"), Address::Unix("/tmp/sock".into()));
+        assert_eq!(parse("unix:sock"), Address::Unix("sock".into()));
+        assert_eq!(parse("@name"), Address::Abstract(b"name".to_vec()));
+        assert_eq!(parse("unix:@name"), Address::Abstract(b"name".to_vec()));
+        assert!(error("@").contains("missing abstract socket name"));
+        assert!(error("unix:").contains("missing socket path"));
+    }
+
+    #[test]
+    fn test_parse_inherited() {
+        let parse = |s| Address::parse(s).unwrap();
+        assert_eq!(parse("fd:3"), Address::Fd(3));
+        assert_eq!(parse("systemd"), Address::Systemd(None));
+        assert_eq!(
+            parse("systemd:proxy"),
+            Address::Systemd(Some("proxy".to_string()))
+        );
+        assert!(error("fd:-1").contains("invalid file descriptor"));
+        assert!(error("fd:x").contains("invalid file descriptor"));
+    }
+
+    #[test]
+    fn test_unix_options() {
+        let path = env::temp_dir().join(format!("monetproxy-{}.sock", process::id()));
+        // SAFETY: getegid(2) cannot fail
+        let gid = unsafe { libc::getegid() };
+        let options = UnixOptions {
+            mode: Some(0o640),
+            group: Some(gid),
+        };
+        let listener = Address::Unix(path.clone()).listen(&options).unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+        assert_eq!(meta.gid(), gid);
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_activation() {
+        let sockets = |fds: &[RawFd], names: &[&str]| {
+            let names = names.iter().map(|n| n.to_string());
+            Ok(fds.iter().copied().zip(names).collect())
+        };
+        assert_eq!(Activation::parse("0", ""), sockets(&[], &[]));
+        assert_eq!(
+            Activation::parse("2", "web"),
+            sockets(&[3, 4], &["web", "unknown"])
+        );
+        for count in ["", "x", "-1", "2147483647", "99999999999"] {
+            assert!(Activation::parse(count, "").is_err(), "{count}");
         }
     }
 }
@@ -1,81 +1,122 @@
 use std::io;
-
+use std::ops::Range;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
+use crate::filter::Filter;
 use crate::formatter::Formatter;
-use crate::formatter::{print_message, Side};
+use crate::formatter::{Collector, Preparer, Side};
 use crate::proxy::Observer;
 
 const CLOSE_MESSAGE: &str = "closed its side of the connection";
 
-struct Blocks {
-    buffer: Vec<u8>,
-    goal: usize,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// A message starts here
+    Start,
+    /// A block header has just been read
+    Header(u16),
+    /// Part of the payload of a block
+    Payload(Range<usize>),
+    /// A block ends here, true if it is the last block of the message
+    BlockEnd(bool),
+    /// A message ends here
+    End,
+}
+
+/// Keeps track of the MAPI block structure of a stream.
+#[derive(Debug, Default)]
+pub struct Blocks {
+    header: [u8; 2],
+    header_len: usize,
+    /// Payload bytes left in the current block
+    remaining: usize,
     last_block: bool,
+    in_message: bool,
+    /// The payload of the current block, only used by [`Blocks::process`]
+    buffer: Vec<u8>,
 }
 
 impl Blocks {
-    fn new() -> Blocks {
-        Blocks {
-            buffer: Vec::with_capacity(8192),
-            goal: 2,
-            last_block: false,
+    /// Find the message and block boundaries in the next piece of the
+    /// stream, without buffering it.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<(usize, Frame)> {
+        let mut frames = vec![];
+        let mut pos = 0;
+        while pos < data.len() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(data.len() - pos);
+                frames.push((pos, Frame::Payload(pos..pos + n)));
+                pos += n;
+                self.remaining -= n;
+                if self.remaining == 0 {
+                    self.end_of_block(pos, &mut frames);
+                }
+                continue;
+            }
+
+            if !self.in_message {
+                self.in_message = true;
+                frames.push((pos, Frame::Start));
+            }
+            self.header[self.header_len] = data[pos];
+            self.header_len += 1;
+            pos += 1;
+            if self.header_len == 2 {
+                let header = u16::from_le_bytes(self.header);
+                self.header_len = 0;
+                self.remaining = (header / 2) as usize;
+                self.last_block = header & 1 != 0;
+                frames.push((pos, Frame::Header(header)));
+                if self.remaining == 0 {
+                    self.end_of_block(pos, &mut frames);
+                }
+            }
         }
+        frames
     }
 
+    /// Whether the stream is in the middle of a message.
+    pub fn in_message(&self) -> bool {
+        self.in_message
+    }
+
+    fn end_of_block(&mut self, pos: usize, frames: &mut Vec<(usize, Frame)>) {
+        frames.push((pos, Frame::BlockEnd(self.last_block)));
+        if self.last_block {
+            self.in_message = false;
+            frames.push((pos, Frame::End));
+        }
+    }
+
+    /// Call the callback with the payload of every complete block and
+    /// whether it ends the message.
     fn process(
         &mut self,
-        mut data: &[u8],
+        data: &[u8],
         callback: &mut dyn FnMut(&[u8], bool) -> io::Result<()>,
     ) -> io::Result<()> {
-        while !data.is_empty() {
-            // Move some data into the buffer, there must be some room
-            assert!(self.buffer.len() < self.goal);
-            let to_read = self.goal - self.buffer.len();
-            let n = data.len().min(to_read);
-            let (append, rest) = data.split_at(n);
-            self.buffer.extend_from_slice(append);
-            data = rest;
-
-            if self.buffer.len() < self.goal {
-                continue;
-            }
-
-            // We've reached the goal.
-            // Three situations to consider
-            // 1. we've just completed reading the header of a nonempty block
-            // 2. we've just completed reading the headerr of an empty block
-            // 3. we've just completed reading the data of a block
-            assert!(self.goal >= 2);
-            if self.goal == 2 {
-                // we've read the header, process it
-                let header: [u8; 2] = self.buffer[..2].try_into().unwrap();
-                let header = u16::from_le_bytes(header);
-                self.goal += (header / 2) as usize;
-                self.last_block = (header & 1) != 0;
-
-                if self.goal > 2 {
-                    // situation 1.
-                    continue;
+        for (_, frame) in self.feed(data) {
+            match frame {
+                Frame::Payload(range) => self.buffer.extend_from_slice(&data[range]),
+                Frame::BlockEnd(last) => {
+                    let result = callback(&self.buffer, last);
+                    self.buffer.clear();
+                    result?;
                 }
-                // situation 2. fall through to situation 3.
+                Frame::Start | Frame::Header(_) | Frame::End => {}
             }
-            // situation 2 or 3
-            let result = callback(&self.buffer[2..], self.last_block);
-            self.buffer.clear();
-            self.goal = 2;
-            result?;
         }
         Ok(())
     }
 
     fn describe_eof(&mut self) -> &'static str {
-        let n = self.buffer.len();
-        assert_ne!(n, self.goal);
-        match n {
-            0 => CLOSE_MESSAGE,
-            1 => "eof on incomplete block header",
-            _ => "eof on incomplete block body",
+        if self.header_len > 0 {
+            "eof on incomplete block header"
+        } else if self.remaining > 0 {
+            "eof on incomplete block body"
+        } else {
+            CLOSE_MESSAGE
         }
     }
 }
@@ -84,16 +125,26 @@ pub struct MessageObserver<F> {
     formatter: Arc<Mutex<F>>,
     side: Side,
     blocks: Blocks,
-    message: Vec<u8>,
+    preparer: Preparer,
+    message: Collector,
+    filter: Arc<Filter>,
 }
 
 impl<F: Formatter> MessageObserver<F> {
-    pub fn new(side: Side, formatter: Arc<Mutex<F>>) -> MessageObserver<F> {
+    /// Only prints the messages the filter accepts.
+    pub fn new(side: Side, formatter: Arc<Mutex<F>>, filter: Arc<Filter>) -> MessageObserver<F> {
+        let preparer = formatter.lock().unwrap().preparer();
+        // The filter may have to look at all of the message
+        let keep = preparer
+            .keep()
+            .filter(|_| filter.kinds.is_empty() && filter.matching.is_empty());
         MessageObserver {
             formatter,
             side,
-            blocks: Blocks::new(),
-            message: Vec::new(),
+            blocks: Blocks::default(),
+            preparer,
+            message: Collector::new(keep),
+            filter,
         }
     }
 }
@@ -101,15 +152,15 @@ impl<F: Formatter> MessageObserver<F> {
 impl<F: Formatter + Send> Observer for MessageObserver<F> {
     fn on_data(&mut self, data: &[u8]) -> io::Result<()> {
         self.blocks.process(data, &mut |block, is_last| {
-            self.message.extend_from_slice(block);
+            self.message.push(block);
             if is_last {
-                let mut f = self.formatter.lock().unwrap();
-                let result = print_message(&mut *f, self.side, &self.message, &[]);
-                self.message.clear();
-                result
-            } else {
-                Ok(())
+                let message = self.message.take();
+                if self.filter.accepts(self.side, message.data()) {
+                    let prepared = self.preparer.prepare(self.side, message, &[])?;
+                    self.formatter.lock().unwrap().print(prepared)?;
+                }
             }
+            Ok(())
         })?;
 
         Ok(())
@@ -117,105 +168,206 @@ impl<F: Formatter + Send> Observer for MessageObserver<F> {
 
     fn on_close(&mut self) -> io::Result<()> {
         let message = self.blocks.describe_eof();
-        self.formatter.lock().unwrap().message(self.side, message)
+        self.message(message)
     }
 
     fn on_error(&mut self, while_writing: bool, err: &io::Error) -> io::Result<()> {
         let action = describe_error(self.side, while_writing);
         let msg = format!("{action}: {err}");
-        self.formatter.lock().unwrap().message(self.side, &msg)
+        self.message(&msg)
     }
 
     fn on_unix0(&mut self, _data: &[u8], _message: Option<&str>) -> io::Result<()> {
         // ignore
         Ok(())
     }
+
+    fn message(&mut self, message: &str) -> io::Result<()> {
+        self.formatter.lock().unwrap().message(self.side, message)
+    }
 }
 
 pub struct RawObserver<F> {
     formatter: Arc<Mutex<F>>,
     side: Side,
+    preparer: Preparer,
 }
 
 impl<F: Formatter + Send> RawObserver<F> {
     pub fn new(side: Side, formatter: Arc<Mutex<F>>) -> RawObserver<F> {
-        RawObserver { formatter, side }
+        let preparer = formatter.lock().unwrap().preparer();
+        RawObserver {
+            formatter,
+            side,
+            preparer,
+        }
     }
 }
 
 impl<F: Formatter + Send> Observer for RawObserver<F> {
     fn on_data(&mut self, data: &[u8]) -> io::Result<()> {
-        let mut f = self.formatter.lock().unwrap();
-        print_message(&mut *f, self.side, data, &[])
+        let prepared = self.preparer.prepare(self.side, data.into(), &[])?;
+        self.formatter.lock().unwrap().print(prepared)
     }
 
     fn on_close(&mut self) -> io::Result<()> {
-        self.formatter.lock().unwrap().message(self.side, CLOSE_MESSAGE)
+        self.message(CLOSE_MESSAGE)
     }
 
     fn on_error(&mut self, while_writing: bool, err: &io::Error) -> io::Result<()> {
         let action = describe_error(self.side, while_writing);
         let msg = format!("{action}: {err}");
-        self.formatter.lock().unwrap().message(self.side, &msg)
+        self.message(&msg)
     }
 
     fn on_unix0(&mut self, data: &[u8], message: Option<&str>) -> io::Result<()> {
         self.on_data(data)?;
         if let Some(m) = message {
-            self.formatter.lock().unwrap().message(self.side, m)?
+            self.message(m)?
         }
         Ok(())
     }
+
+    fn message(&mut self, message: &str) -> io::Result<()> {
+        self.formatter.lock().unwrap().message(self.side, message)
+    }
 }
 
 pub struct BlockObserver<F> {
     formatter: Arc<Mutex<F>>,
     side: Side,
     blocks: Blocks,
+    preparer: Preparer,
 }
 
 impl<F: Formatter + Send> BlockObserver<F> {
     pub fn new(side: Side, formatter: Arc<Mutex<F>>) -> BlockObserver<F> {
+        let preparer = formatter.lock().unwrap().preparer();
         BlockObserver {
             formatter,
             side,
-            blocks: Blocks::new(),
+            blocks: Blocks::default(),
+            preparer,
         }
     }
 }
 
 impl<F: Formatter + Send> Observer for BlockObserver<F> {
     fn on_data(&mut self, data: &[u8]) -> io::Result<()> {
-        let mut f = self.formatter.lock().unwrap();
         self.blocks.process(data, &mut |block, is_last| {
             let remarks = [if is_last {
                 "ends the message"
             } else {
                 "does not end the message"
             }];
-            print_message(&mut *f, self.side, block, &remarks)
+            let prepared = self.preparer.prepare(self.side, block.into(), &remarks)?;
+            self.formatter.lock().unwrap().print(prepared)
         })
     }
 
     fn on_close(&mut self) -> io::Result<()> {
         let message = self.blocks.describe_eof();
-        self.formatter.lock().unwrap().message(self.side, message)
+        self.message(message)
     }
 
     fn on_error(&mut self, while_writing: bool, err: &io::Error) -> io::Result<()> {
         let action = describe_error(self.side, while_writing);
         let msg = format!("{action}: {err}");
-        self.formatter.lock().unwrap().message(self.side, &msg)
+        self.message(&msg)
     }
 
     fn on_unix0(&mut self, _data: &[u8], message: Option<&str>) -> io::Result<()> {
         if let Some(m) = message {
-            self.formatter.lock().unwrap().message(self.side, m)?
+            self.message(m)?
         }
         Ok(())
     }
+
+    fn message(&mut self, message: &str) -> io::Result<()> {
+        self.formatter.lock().unwrap().message(self.side, message)
+    }
 }
 
+/// Does not look at the data at all, only reports how much was forwarded
+/// and when.
+pub struct TransferObserver<F> {
+    formatter: Arc<Mutex<F>>,
+    side: Side,
+    started: Instant,
+    first: Option<Instant>,
+    last: Option<Instant>,
+    nbytes: u64,
+}
+
+impl<F: Formatter + Send> TransferObserver<F> {
+    pub fn new(side: Side, formatter: Arc<Mutex<F>>) -> TransferObserver<F> {
+        TransferObserver {
+            formatter,
+            side,
+            started: Instant::now(),
+            first: None,
+            last: None,
+            nbytes: 0,
+        }
+    }
+
+    fn summary(&self) -> String {
+        let (Some(first), Some(last)) = (self.first, self.last) else {
+            return "forwarded nothing".to_string();
+        };
+        let first_after = (first - self.started).as_secs_f64();
+        let duration = (last - first).as_secs_f64();
+        let mut msg = format!(
+            "forwarded {n} bytes, first after {first_after:.3}s, last {duration:.3}s later",
+            n = self.nbytes
+        );
+        if duration > 0.0 {
+            let rate = self.nbytes as f64 / duration / 1e6;
+            msg.push_str(&format!(" ({rate:.1} MB/s)"));
+        }
+        msg
+    }
+}
+
+impl<F: Formatter + Send> Observer for TransferObserver<F> {
+    fn on_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.on_forwarded(data.len())
+    }
+
+    fn on_close(&mut self) -> io::Result<()> {
+        let msg = format!("{CLOSE_MESSAGE}, {}", self.summary());
+        self.message(&msg)
+    }
+
+    fn on_error(&mut self, while_writing: bool, err: &io::Error) -> io::Result<()> {
+        let action = describe_error(self.side, while_writing);
+        let msg = format!("{action}: {err}, {}", self.summary());
+        self.message(&msg)
+    }
+
+    fn on_unix0(&mut self, _data: &[u8], message: Option<&str>) -> io::Result<()> {
+        if let Some(m) = message {
+            self.message(m)?
+        }
+        Ok(())
+    }
+
+    fn message(&mut self, message: &str) -> io::Result<()> {
+        self.formatter.lock().unwrap().message(self.side, message)
+    }
+
+    fn wants_data(&self) -> bool {
+        false
+    }
+
+    fn on_forwarded(&mut self, nbytes: usize) -> io::Result<()> {
+        let now = Instant::now();
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+        self.nbytes += nbytes as u64;
+        Ok(())
+    }
+}
 
 fn describe_error(side: Side, while_writing: bool) -> &'static str {
     match (side, while_writing) {
@@ -223,4 +375,116 @@ fn describe_error(side: Side, while_writing: bool) -> &'static str {
         (Side::Server, true) => "encountered an error writing to client",
         (_, false) => "could not be read",
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use crate::formatter::TextFormatter;
+    use crate::proxy;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_feed_split_header() {
+        let mut blocks = Blocks::default();
+        assert_eq!(blocks.feed(&[5]), [(0, Frame::Start)]);
+        assert!(blocks.in_message());
+        assert_eq!(
+            blocks.feed(&[0, b'a', b'b']),
+            [
+                (1, Frame::Header(5)),
+                (1, Frame::Payload(1..3)),
+                (3, Frame::BlockEnd(true)),
+                (3, Frame::End),
+            ]
+        );
+        assert!(!blocks.in_message());
+    }
+
+    #[test]
+    fn test_feed_several_messages() {
+        let mut blocks = Blocks::default();
+        assert_eq!(
+            blocks.feed(&[5, 0, b'a', b'b', 3, 0, b'c']),
+            [
+                (0, Frame::Start),
+                (2, Frame::Header(5)),
+                (2, Frame::Payload(2..4)),
+                (4, Frame::BlockEnd(true)),
+                (4, Frame::End),
+                (4, Frame::Start),
+                (6, Frame::Header(3)),
+                (6, Frame::Payload(6..7)),
+                (7, Frame::BlockEnd(true)),
+                (7, Frame::End),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_feed_empty_final_block() {
+        let mut blocks = Blocks::default();
+        assert_eq!(
+            blocks.feed(&[4, 0, b'a', b'b', 1, 0]),
+            [
+                (0, Frame::Start),
+                (2, Frame::Header(4)),
+                (2, Frame::Payload(2..4)),
+                (4, Frame::BlockEnd(false)),
+                (6, Frame::Header(1)),
+                (6, Frame::BlockEnd(true)),
+                (6, Frame::End),
+            ]
+        );
+        // An empty message, the server's prompt
+        assert_eq!(
+            blocks.feed(&[1, 0]),
+            [
+                (0, Frame::Start),
+                (2, Frame::Header(1)),
+                (2, Frame::BlockEnd(true)),
+                (2, Frame::End),
+            ]
+        );
+        assert!(!blocks.in_message());
+    }
+
+    #[test]
+    fn test_unix0() {
+        // The proxy takes the '0' off before the observer sees the data,
+        // otherwise it would be read as half a block header
+        let stream = [b'0', 5, 0, b'a', b'b'];
+        proxy::check_unix0(stream[0]).unwrap();
+        assert!(proxy::check_unix0(stream[1]).is_err());
+
+        let output = Output::default();
+        let formatter = Arc::new(Mutex::new(TextFormatter::new(output.clone())));
+        let mut observer = BlockObserver::new(Side::Client, Arc::clone(&formatter));
+        let (data, message) = proxy::unix0_remark(true, false).unwrap();
+        observer.on_unix0(data, message).unwrap();
+        observer.on_data(&stream[1..]).unwrap();
+        assert!(!observer.blocks.in_message());
+        observer.on_close().unwrap();
+        formatter.lock().unwrap().flush().unwrap();
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("proxy eliminated leading '0'"));
+        assert!(output.contains("CLIENT text, 2 bytes, no trailing newline, ends the message"));
+        assert!(output.contains(CLOSE_MESSAGE));
+    }
+}
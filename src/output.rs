@@ -0,0 +1,263 @@
+//! Writing the output on a thread of its own, so a slow terminal or pipe
+//! does not slow down the traffic being observed. The messages are queued
+//! as they are and formatted on that thread too. With --split the files of
+//! the connections are written by that same thread.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+use crate::formatter::{Formatter, Prepared, Side, TextFormatter};
+use crate::proxy;
+
+/// What to do when the output thread falls behind and the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhenBehind {
+    /// Wait for room in the queue, slowing down the traffic
+    Wait,
+    /// Leave out the event and report how many were left out
+    Drop,
+}
+
+/// Something for the output thread to write, see the methods of
+/// [`Formatter`] with the same name.
+pub enum Event {
+    Connected(String, String),
+    ProxyMessage(String),
+    Message(Side, String),
+    Print(Prepared<'static>),
+    /// Reopen the output file, see [`TextFormatter::reopen`]
+    Reopen,
+    /// Start writing to the formatter of a connection, see [`Queue::open`]
+    Open(Box<TextFormatter>),
+    /// Close the formatter of a connection
+    Close,
+}
+
+impl Event {
+    /// Bytes it takes up in the queue.
+    fn size(&self) -> usize {
+        match self {
+            Event::Connected(client, server) => client.len() + server.len(),
+            Event::ProxyMessage(message) | Event::Message(_, message) => message.len(),
+            Event::Print(message) => message.size(),
+            Event::Reopen | Event::Open(_) | Event::Close => 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Each with its target and the number of events for that target
+    /// dropped just before it
+    events: VecDeque<(u64, u64, Event)>,
+    bytes: usize,
+    /// By target
+    dropped: HashMap<u64, u64>,
+    /// The last target handed out by [`Queue::open`]
+    last_target: u64,
+    /// Targets whose output could not be written
+    failed: HashSet<u64>,
+    closed: bool,
+    /// The output thread has stopped, after an error
+    stopped: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    /// In bytes
+    capacity: usize,
+}
+
+/// Passes events to a formatter on the output thread.
+pub struct Queue {
+    shared: Arc<Shared>,
+    /// Which formatter on the output thread the events are for, 0 for the
+    /// one the queue was created with
+    target: u64,
+    /// Only for that first one
+    thread: Option<JoinHandle<()>>,
+    /// Whether to drop events when the queue is full, see [`Queue::dropping`]
+    dropping: Arc<AtomicBool>,
+}
+
+impl Queue {
+    /// Holds up to `capacity` bytes of messages. A larger message is only
+    /// queued once the queue is empty.
+    pub fn new(formatter: TextFormatter, capacity: usize, when_behind: WhenBehind) -> Queue {
+        let shared = Arc::new(Shared {
+            state: Mutex::default(),
+            changed: Condvar::new(),
+            capacity,
+        });
+        let cloned = Arc::clone(&shared);
+        let thread = proxy::spawn_worker("output", move || {
+            let result = write_events(&cloned, formatter);
+            cloned.state.lock().unwrap().stopped = true;
+            cloned.changed.notify_all();
+            result
+        });
+        Queue {
+            shared,
+            target: 0,
+            thread: Some(thread),
+            dropping: Arc::new(AtomicBool::new(when_behind == WhenBehind::Drop)),
+        }
+    }
+
+    /// Clear it to stop dropping events, for example to make sure the last
+    /// ones are written.
+    pub fn dropping(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.dropping)
+    }
+
+    /// A queue for another formatter, written by the same thread and
+    /// sharing the capacity.
+    pub fn open(&self, formatter: TextFormatter) -> io::Result<Queue> {
+        let target = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.last_target += 1;
+            state.last_target
+        };
+        let queue = Queue {
+            shared: Arc::clone(&self.shared),
+            target,
+            thread: None,
+            dropping: Arc::clone(&self.dropping),
+        };
+        queue.push(Event::Open(Box::new(formatter)), false)?;
+        Ok(queue)
+    }
+
+    pub fn send(&self, event: Event) -> io::Result<()> {
+        self.push(event, true)
+    }
+
+    fn push(&self, event: Event, may_drop: bool) -> io::Result<()> {
+        let size = event.size();
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.stopped {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "the output thread has stopped",
+                ));
+            }
+            if state.failed.contains(&self.target) {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "the output could not be written",
+                ));
+            }
+            if state.events.is_empty() || state.bytes + size <= self.shared.capacity {
+                break;
+            }
+            if may_drop && self.dropping.load(Ordering::Relaxed) {
+                *state.dropped.entry(self.target).or_default() += 1;
+                return Ok(());
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        let dropped = state.dropped.remove(&self.target).unwrap_or(0);
+        state.bytes += size;
+        state.events.push_back((self.target, dropped, event));
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for Queue {
+    /// Waits until everything has been written.
+    fn drop(&mut self) {
+        if self.thread.is_none() {
+            let _ = self.push(Event::Close, false);
+            return;
+        }
+        // Nothing may be left out at the end
+        self.dropping.store(false, Ordering::Relaxed);
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write_events(shared: &Shared, formatter: TextFormatter) -> io::Result<()> {
+    let mut formatters = HashMap::from([(0, formatter)]);
+    loop {
+        let (target, dropped, event) = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if let Some((target, dropped, event)) = state.events.pop_front() {
+                    state.bytes -= event.size();
+                    shared.changed.notify_all();
+                    break (target, dropped, Some(event));
+                }
+                if state.closed {
+                    let dropped = state.dropped.remove(&0).unwrap_or(0);
+                    break (0, dropped, None);
+                }
+                state = shared.changed.wait(state).unwrap();
+            }
+        };
+        let last = event.is_none();
+        match write_event(
+            &mut formatters,
+            target,
+            dropped,
+            event.unwrap_or(Event::Close),
+        ) {
+            Ok(()) => {}
+            Err(e) if target == 0 => return Err(e),
+            // Only the output of that connection is lost
+            Err(_) => {
+                formatters.remove(&target);
+                shared.state.lock().unwrap().failed.insert(target);
+                shared.changed.notify_all();
+            }
+        }
+        if last {
+            // Connections that are still open
+            for (_, mut formatter) in formatters {
+                formatter.close()?;
+            }
+            return Ok(());
+        }
+    }
+}
+
+fn write_event(
+    formatters: &mut HashMap<u64, TextFormatter>,
+    target: u64,
+    dropped: u64,
+    event: Event,
+) -> io::Result<()> {
+    if let Event::Open(formatter) = event {
+        formatters.insert(target, *formatter);
+        return Ok(());
+    }
+    let Some(formatter) = formatters.get_mut(&target) else {
+        return Ok(());
+    };
+    if dropped > 0 {
+        let message = format!("{dropped} events dropped, the output could not keep up");
+        formatter.proxy_message(&message)?;
+    }
+    match event {
+        Event::Connected(client, server) => formatter.connected(&client, &server),
+        Event::ProxyMessage(message) => formatter.proxy_message(&message),
+        Event::Message(side, message) => formatter.message(side, &message),
+        Event::Print(message) => formatter.print(message),
+        Event::Reopen => formatter.reopen(),
+        Event::Open(_) => Ok(()),
+        Event::Close => {
+            formatter.close()?;
+            formatters.remove(&target);
+            Ok(())
+        }
+    }
+}
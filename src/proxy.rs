@@ -1,13 +1,21 @@
 use std::io::{Read, Write};
-use std::net::{TcpStream, ToSocketAddrs};
-use std::os::unix::net::UnixStream;
-use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::os::fd::RawFd;
+use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use std::{fmt, io};
 
+use crate::capture::{Event, Recorder, Recording};
+use crate::connections::{Connection, Connections, Gate, Ticket, WhenFull};
+use crate::debugger::Command;
 use crate::formatter::{Formatter, Side};
-use crate::network::{Address, Incoming, Outgoing};
+use crate::network::{Accepter, Address, Closer, Incoming, InetAddr, Listener, Outgoing};
+#[cfg(target_os = "linux")]
+use crate::splice::Splicer;
+use crate::tamper::{self, Action, Input, Tamper, TamperConfig};
+use crate::tls::TlsConnector;
 
 pub const BLOCKSIZE: usize = 8190;
 
@@ -16,62 +24,265 @@ pub trait Observer: Send {
     fn on_close(&mut self) -> io::Result<()>;
     fn on_error(&mut self, while_writing: bool, err: &io::Error) -> io::Result<()>;
     fn on_unix0(&mut self, data: &[u8], message: Option<&str>) -> io::Result<()>;
+    /// Say something about this side of the connection.
+    fn message(&mut self, message: &str) -> io::Result<()>;
+
+    /// The proxy did something to the data other than forwarding it.
+    fn on_remark(&mut self, message: &str) -> io::Result<()> {
+        self.message(message)
+    }
+
+    /// Observers that return false here are not interested in the payload,
+    /// the data may then be forwarded without passing through the proxy's
+    /// memory and [`Observer::on_forwarded`] is called instead of
+    /// [`Observer::on_data`].
+    fn wants_data(&self) -> bool {
+        true
+    }
+
+    fn on_forwarded(&mut self, _nbytes: usize) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where to forward each client connection to, and how.
+#[derive(Debug, Clone)]
+pub struct Destination {
+    pub addr: Address,
+    pub tls: Option<TlsConnector>,
 }
 
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.tls.is_some() {
+            f.write_str("tls:")?;
+        }
+        self.addr.fmt(f)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn spawn_listener<O, I, F>(
-    addr: Address,
-    forward_to: Address,
+    listener: Listener,
+    gate: Gate,
+    forward_to: Destination,
+    tamper: Arc<TamperConfig>,
+    connections: Arc<Connections>,
     formatter: Arc<Mutex<O>>,
+    recorder: Option<Arc<Recorder>>,
     make_inspector: F,
 ) -> JoinHandle<()>
 where
     O: Formatter + Send + 'static,
     I: Observer + Send + 'static,
-    F: FnMut(Side, Arc<Mutex<O>>) -> I + Send + Sync + 'static,
+    F: Fn(Side, Arc<Mutex<O>>) -> I + Send + Sync + 'static,
 {
-    spawn_worker(addr.to_string(), move || {
-        listen(addr, formatter, make_inspector, forward_to)
+    let Listener {
+        accepter, local, ..
+    } = listener;
+    let shared = Arc::new(Shared {
+        local,
+        connections,
+        formatter,
+        recorder,
+        make_inspector,
+        forward_to,
+        tamper,
+    });
+    spawn_worker(shared.local.to_string(), move || {
+        listen(accepter, gate, shared)
     })
 }
 
-fn listen<O, I, F>(
-    addr: Address,
+/// What the threads of the connections accepted by a listener share.
+struct Shared<O, F> {
+    local: Address,
+    connections: Arc<Connections>,
     formatter: Arc<Mutex<O>>,
-    mut make_inspector: F,
-    forward_to: Address,
+    recorder: Option<Arc<Recorder>>,
+    make_inspector: F,
+    forward_to: Destination,
+    tamper: Arc<TamperConfig>,
+}
+
+/// Accept clients and hand each one to a thread of its own, which connects
+/// it to the server. Problems with one client are reported and do not stop
+/// the listener.
+fn listen<O, I, F>(
+    mut accepter: Box<Accepter>,
+    gate: Gate,
+    shared: Arc<Shared<O, F>>,
 ) -> io::Result<()>
 where
-    O: Formatter,
+    O: Formatter + Send + 'static,
     I: Observer + Send + 'static,
-    F: FnMut(Side, Arc<Mutex<O>>) -> I + Send + Sync + 'static,
+    F: Fn(Side, Arc<Mutex<O>>) -> I + Send + Sync + 'static,
 {
-    let mut accepter = addr.listen()?;
-    eprintln!("Listening on {addr}");
+    let addr = &shared.local;
+    let connections = &shared.connections;
     loop {
-        let (mut from_client, to_client, client_address) = accepter()?;
+        let (from_client, mut to_client, client_address) = match accepter() {
+            Err(_) if connections.shutting_down() => return Ok(()),
+            Err(e) => {
+                let msg = format!("could not accept a connection on {addr}: {e}");
+                shared.formatter.lock().unwrap().proxy_message(&msg)?;
+                // Out of file descriptors, for example, give others a chance
+                thread::sleep(ACCEPT_RETRY);
+                continue;
+            }
+            Ok(accepted) => accepted,
+        };
+
+        // When queueing, the client waits here without being connected to
+        // the server, and the next clients wait in the listen queue
+        let wait = gate.when_full == WhenFull::Queue;
+        let ticket = match connections.admit(&gate, wait) {
+            Ok(ticket) => ticket,
+            Err(_) if connections.shutting_down() => return Ok(()),
+            Err(reason) => {
+                let _ = refuse(&mut to_client, &reason);
+                let msg = format!("refused connection on {addr}: {reason}");
+                shared.formatter.lock().unwrap().proxy_message(&msg)?;
+                continue;
+            }
+        };
 
-        let (from_server, mut to_server, server_address) = connect(&forward_to)?;
-        formatter
+        let shared = Arc::clone(&shared);
+        spawn_worker(format!("upstream-{client_address}"), move || {
+            let client = (from_client, to_client, client_address);
+            shared.serve(client, ticket)
+        });
+    }
+}
+
+/// How long to wait before accepting again after accepting failed.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+impl<O, I, F> Shared<O, F>
+where
+    O: Formatter + Send + 'static,
+    I: Observer + Send + 'static,
+    F: Fn(Side, Arc<Mutex<O>>) -> I + Send + Sync + 'static,
+{
+    /// Connect the client to the server and forward from the client to
+    /// the server, the other direction gets a thread of its own.
+    fn serve(&self, client: (Incoming, Outgoing, Address), ticket: Ticket) -> io::Result<()> {
+        let (mut from_client, to_client, client_address) = client;
+        let mut to_client = Some(to_client);
+        let started = self.start(&mut from_client, &mut to_client, &client_address, ticket);
+        let (inspect_client, tamper_client, to_server, conn) = match started {
+            Ok(Some(started)) => started,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                if let Some(mut w) = to_client {
+                    let _ = refuse(&mut w, "the proxy could not connect to the server");
+                }
+                let msg = format!(
+                    "could not connect {client_address} on {} to {}: {e}",
+                    self.local, self.forward_to
+                );
+                return self.formatter.lock().unwrap().proxy_message(&msg);
+            }
+        };
+        pump(
+            inspect_client,
+            tamper_client,
+            from_client,
+            to_server,
+            &conn,
+            Side::Client,
+        )
+    }
+
+    /// Everything up to forwarding from the client: connect to the server,
+    /// register the connection, set up its observers and start the thread
+    /// forwarding from the server, which takes `to_client`. None if the
+    /// proxy is shutting down.
+    #[allow(clippy::type_complexity)]
+    fn start(
+        &self,
+        from_client: &mut Incoming,
+        to_client: &mut Option<Outgoing>,
+        client_address: &Address,
+        ticket: Ticket,
+    ) -> io::Result<Option<(Recording<I>, Option<Tamper>, Outgoing, Arc<Connection>)>> {
+        let addr = &self.local;
+        let (from_server, mut to_server, server_address) = connect(&self.forward_to)?;
+        if self.connections.shutting_down() {
+            return Ok(None);
+        }
+        let server_address = if self.forward_to.tls.is_some() {
+            format!("tls:{server_address}")
+        } else {
+            server_address.to_string()
+        };
+
+        let closers = vec![from_client.closer()?, from_server.closer()?];
+        let conn = self.connections.register(closers, ticket);
+        self.formatter
             .lock()
             .unwrap()
-            .connected(&addr, &server_address)?;
+            .connected(addr, &server_address)?;
 
-        let mut inspect_client = make_inspector(Side::Client, Arc::clone(&formatter));
-        let inspect_server = make_inspector(Side::Server, Arc::clone(&formatter));
+        if let Some(recorder) = &self.recorder {
+            let (client, server) = (client_address.to_string(), server_address.clone());
+            recorder.record(conn.id, Event::Connected { client, server })?;
+        }
+        let own =
+            self.formatter
+                .lock()
+                .unwrap()
+                .for_connection(conn.id, client_address, &server_address);
+        let formatter = match own {
+            Ok(Some(mut own)) => {
+                own.connected(addr, &server_address)?;
+                Arc::new(Mutex::new(own))
+            }
+            Ok(None) => Arc::clone(&self.formatter),
+            Err(e) => {
+                let message = format!(
+                    "could not write connection {} to a file of its own, it is shown here: {e}",
+                    conn.id
+                );
+                self.formatter.lock().unwrap().proxy_message(&message)?;
+                Arc::clone(&self.formatter)
+            }
+        };
+        let inspect_client = (self.make_inspector)(Side::Client, Arc::clone(&formatter));
+        let inspect_server = (self.make_inspector)(Side::Server, Arc::clone(&formatter));
+        let mut inspect_client =
+            Recording::new(inspect_client, self.recorder.clone(), conn.id, Side::Client);
+        let inspect_server =
+            Recording::new(inspect_server, self.recorder.clone(), conn.id, Side::Server);
+        let (tamper_client, tamper_server) = tamper::for_connection(&self.tamper).unzip();
+        adjust_unix(&mut inspect_client, from_client, &mut to_server)?;
 
-        spawn_worker(format!("downstream-{client_address}"), || {
-            pump(inspect_server, from_server, to_client)
-        });
-        spawn_worker(format!("upstream-{client_address}"), move || {
-            adjust_unix(&mut inspect_client, &mut from_client, &mut to_server)?;
-            pump(inspect_client, from_client, to_server)
+        let conn2 = Arc::clone(&conn);
+        let to_client = to_client.take().unwrap();
+        spawn_worker(format!("downstream-{client_address}"), move || {
+            pump(
+                inspect_server,
+                tamper_server,
+                from_server,
+                to_client,
+                &conn2,
+                Side::Server,
+            )
         });
+        Ok(Some((inspect_client, tamper_client, to_server, conn)))
     }
 }
 
-fn connect(addr: &Address) -> io::Result<(Incoming, Outgoing, Address)> {
-    if let Some(Address::Unix(path)) = addr.to_unix() {
-        if let Ok(tuple) = connect_unix(path) {
+fn connect(dest: &Destination) -> io::Result<(Incoming, Outgoing, Address)> {
+    let addr = &dest.addr;
+
+    if let Some(tls) = &dest.tls {
+        // TLS is only spoken over TCP
+        if let Some(Address::Inet(a)) = addr.to_inet() {
+            return connect_tls(tls, a);
+        }
+    } else if let Some(unix) = addr.to_unix() {
+        if let Ok(tuple) = connect_unix(unix) {
             return Ok(tuple);
         }
     }
@@ -81,58 +292,93 @@ fn connect(addr: &Address) -> io::Result<(Incoming, Outgoing, Address)> {
     }
 
     let kind: io::ErrorKind = io::ErrorKind::ConnectionRefused;
-    Err(io::Error::new(kind, format!("can't connect to {addr}")))
+    Err(io::Error::new(kind, format!("can't connect to {dest}")))
 }
 
-fn connect_inet<A: ToSocketAddrs>(addr: A) -> io::Result<(Incoming, Outgoing, Address)> {
-    let conn1 = TcpStream::connect(addr)?;
+fn connect_inet(addr: InetAddr) -> io::Result<(Incoming, Outgoing, Address)> {
+    let conn1 = addr.connect()?;
     let conn2 = conn1.try_clone()?;
     let peer = conn1.peer_addr()?;
     Ok((
         Incoming::Inet(conn1),
         Outgoing::Inet(conn2),
-        Address::Inet(peer.to_string()),
+        Address::Inet(peer.into()),
+    ))
+}
+
+fn connect_tls(tls: &TlsConnector, addr: InetAddr) -> io::Result<(Incoming, Outgoing, Address)> {
+    let conn = addr.connect()?;
+    let peer = conn.peer_addr()?;
+    let (r, w) = tls.connect(&addr.host.to_string(), conn)?;
+    Ok((
+        Incoming::Tls(r),
+        Outgoing::Tls(w),
+        Address::Inet(peer.into()),
     ))
 }
 
-fn connect_unix(p: impl AsRef<Path>) -> io::Result<(Incoming, Outgoing, Address)> {
-    let p = p.as_ref();
-    let conn1 = UnixStream::connect(p)?;
+pub fn connect_unix(addr: Address) -> io::Result<(Incoming, Outgoing, Address)> {
+    let conn1 = addr.connect_unix()?;
     let conn2 = conn1.try_clone()?;
-    let peer = Address::Unix(p.into());
 
-    Ok((Incoming::Unix(conn1), Outgoing::Unix(conn2), peer))
+    Ok((Incoming::Unix(conn1), Outgoing::Unix(conn2), addr))
+}
+
+/// Send the client an error message in the place where it expects the
+/// server's challenge, and close the connection.
+pub fn refuse(w: &mut Outgoing, reason: &str) -> io::Result<()> {
+    let message = format!("!{reason}\n");
+    w.write_all(&tamper::frame(message.as_bytes()))?;
+    w.shutdown()
 }
 
 fn adjust_unix(observer: &mut dyn Observer, r: &mut Incoming, w: &mut Outgoing) -> io::Result<()> {
     remove_unix0(r)?;
-    match (&r, &w) {
-        (Incoming::Inet(_), Outgoing::Inet(_)) => {}
-        (Incoming::Inet(_), Outgoing::Unix(_)) => observer.on_unix0(
+    let from_unix = matches!(r, Incoming::Unix(_));
+    let to_unix = matches!(w, Outgoing::Unix(_));
+    if let Some((data, message)) = unix0_remark(from_unix, to_unix) {
+        observer.on_unix0(data, message)?;
+    }
+    insert_unix0(w)
+}
+
+/// What to tell the observer about the leading '0' that clients send
+/// over unix domain sockets.
+pub fn unix0_remark(
+    from_unix: bool,
+    to_unix: bool,
+) -> Option<(&'static [u8], Option<&'static str>)> {
+    match (from_unix, to_unix) {
+        (false, false) => None,
+        (false, true) => Some((
             b"",
             Some("proxy inserting leading '0' to adjust inet->unix"),
-        )?,
-        (Incoming::Unix(_), Outgoing::Inet(_)) => observer.on_unix0(
+        )),
+        (true, false) => Some((
             b"0",
             Some("proxy eliminated leading '0' to adjust unix->inet"),
-        )?,
-        (Incoming::Unix(_), Outgoing::Unix(_)) => observer.on_unix0(b"0", None)?,
+        )),
+        (true, true) => Some((b"0", None)),
     }
-    insert_unix0(w)
 }
 
 fn remove_unix0(r: &mut Incoming) -> io::Result<()> {
-    let Incoming::Unix(ref mut r) = r else { return Ok(()) };
+    let Incoming::Unix(ref mut r) = r else {
+        return Ok(());
+    };
 
     let mut buffer = [0u8];
     r.read_exact(&mut buffer)?;
-    if buffer[0] == b'0' {
+    check_unix0(buffer[0])
+}
+
+pub fn check_unix0(byte: u8) -> io::Result<()> {
+    if byte == b'0' {
         Ok(())
     } else {
         let kind = io::ErrorKind::InvalidData;
         let msg = format!(
-            "expected first character from client unix domain socket to be 0x30 ('0'), got 0x{x:02x}",
-            x = buffer[0]
+            "expected first character from client unix domain socket to be 0x30 ('0'), got 0x{byte:02x}",
         );
         Err(io::Error::new(kind, msg))
     }
@@ -145,35 +391,294 @@ fn insert_unix0(w: &mut Outgoing) -> io::Result<()> {
     Ok(())
 }
 
-fn pump(mut inspector: impl Observer, mut r: Incoming, mut w: Outgoing) -> io::Result<()> {
+fn pump(
+    mut inspector: impl Observer,
+    mut tamper: Option<Tamper>,
+    r: Incoming,
+    mut w: Outgoing,
+    conn: &Connection,
+    side: Side,
+) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if !inspector.wants_data() && tamper.is_none() {
+        if let (Some(from), Some(to)) = (r.plain_fd(), w.plain_fd()) {
+            return splice_pump(inspector, r, w, from, to, conn, side);
+        }
+    }
+
+    let mut r = match tamper.as_mut().and_then(Tamper::take_inputs) {
+        Some((sender, inputs)) => Source::spawn(r, sender, inputs, side)?,
+        None => Source::Socket(r),
+    };
     let mut buffer = [0u8; BLOCKSIZE];
 
     loop {
         let nread = match r.read(&mut buffer) {
+            Ok(Received::Reply(reply)) => {
+                let tamper = tamper.as_mut().unwrap();
+                tamper.queue(reply);
+                for reply in tamper.replies() {
+                    if let Err(e) = tamper.write(&mut w, &reply) {
+                        inspector.on_error(true, &explain_error(conn, e))?;
+                        let _ = r.shutdown();
+                        return Ok(());
+                    }
+                }
+                continue;
+            }
             Err(e) => {
-                let result = inspector.on_error(false, &e);
+                let result = inspector.on_error(false, &explain_error(conn, e));
+                let _ = w.shutdown();
+                return result;
+            }
+            Ok(Received::Data(0)) => {
+                if let Some(e) = kill_error(conn) {
+                    inspector.on_error(false, &e)?;
+                } else {
+                    inspector.on_close()?;
+                }
+                let _ = w.shutdown();
+                return Ok(());
+            }
+            Ok(Received::Data(n)) => n,
+        };
+
+        conn.count(side, nread);
+        if inspector.wants_data() {
+            inspector.on_data(&buffer[..nread])?;
+        } else {
+            inspector.on_forwarded(nread)?;
+        }
+
+        let data = &buffer[..nread];
+        let written = match &mut tamper {
+            None => w.write_all(data),
+            Some(tamper) => perform(tamper, data, &mut w, &mut inspector, conn)?,
+        };
+        if let Err(e) = written {
+            inspector.on_error(true, &explain_error(conn, e))?;
+            let _ = r.shutdown();
+            return Ok(());
+        }
+    }
+}
+
+/// Where a pump gets its data. A direction that receives replies from
+/// the other one reads the socket on a thread of its own, so that the
+/// pump can wait for both at once.
+enum Source {
+    Socket(Incoming),
+    Reader(Receiver<Input>, Closer),
+}
+
+enum Received {
+    /// This many bytes were read into the buffer, 0 at the end of the stream
+    Data(usize),
+    Reply(tamper::Reply),
+}
+
+impl Source {
+    fn spawn(
+        mut r: Incoming,
+        sender: Sender<Input>,
+        inputs: Receiver<Input>,
+        side: Side,
+    ) -> io::Result<Source> {
+        let closer = r.closer()?;
+        thread::Builder::new()
+            .name(format!("reader-{side}"))
+            .spawn(move || {
+                let mut buffer = [0u8; BLOCKSIZE];
+                loop {
+                    let read = r.read(&mut buffer).map(|n| buffer[..n].to_vec());
+                    let more = matches!(&read, Ok(data) if !data.is_empty());
+                    // Stop when the pump is gone
+                    if sender.send(Input::Read(read)).is_err() || !more {
+                        return;
+                    }
+                }
+            })?;
+        Ok(Source::Reader(inputs, closer))
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<Received> {
+        match self {
+            Source::Socket(r) => r.read(buffer).map(Received::Data),
+            Source::Reader(inputs, _) => match inputs.recv() {
+                Ok(Input::Read(read)) => {
+                    let data = read?;
+                    buffer[..data.len()].copy_from_slice(&data);
+                    Ok(Received::Data(data.len()))
+                }
+                Ok(Input::Reply(reply)) => Ok(Received::Reply(reply)),
+                // Only if the reader thread died
+                Err(_) => Ok(Received::Data(0)),
+            },
+        }
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Source::Socket(r) => r.shutdown(),
+            Source::Reader(_, closer) => {
+                closer.stop_reading();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for Source {
+    /// Wake up the reader thread if it is still waiting.
+    fn drop(&mut self) {
+        if let Source::Reader(_, closer) = self {
+            closer.stop_reading();
+        }
+    }
+}
+
+/// Carry out the actions decided on by the [`Tamper`]. The outer error is
+/// from the observer, the inner one from writing.
+fn perform(
+    tamper: &mut Tamper,
+    data: &[u8],
+    w: &mut Outgoing,
+    inspector: &mut impl Observer,
+    conn: &Connection,
+) -> io::Result<io::Result<()>> {
+    for action in tamper.process(data) {
+        match action {
+            Action::Forward(range) => {
+                if let Err(e) = tamper.write(w, &data[range]) {
+                    return Ok(Err(e));
+                }
+            }
+            Action::Send(bytes) => {
+                if let Err(e) = tamper.write(w, &bytes) {
+                    return Ok(Err(e));
+                }
+            }
+            Action::Reply(reply) => {
+                if let Err(e) = tamper.reply(reply) {
+                    return Ok(Err(e));
+                }
+            }
+            Action::Break(bytes, message) => {
+                if let Err(e) = step(tamper, bytes, message, w, inspector)? {
+                    return Ok(Err(e));
+                }
+            }
+            Action::Delay(delay) => thread::sleep(delay),
+            Action::Remark(message) => inspector.on_remark(&message)?,
+            // Both pumps will notice and report the reason
+            Action::Close(reason) => conn.kill(&reason),
+            Action::Reset(reason) => conn.reset(&reason),
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Let the user decide what happens to a message.
+fn step(
+    tamper: &mut Tamper,
+    mut bytes: Vec<u8>,
+    mut message: Vec<u8>,
+    w: &mut Outgoing,
+    inspector: &mut impl Observer,
+) -> io::Result<io::Result<()>> {
+    let side = tamper.side();
+    loop {
+        match tamper.ask(&message) {
+            Command::Forward => return Ok(tamper.write(w, &bytes)),
+            Command::Drop => {
+                tamper.count_sent(-1);
+                inspector.on_remark("dropped by the debugger")?;
+                return Ok(Ok(()));
+            }
+            Command::Edit(edited) => {
+                let text = String::from_utf8_lossy(&edited);
+                inspector.on_remark(&format!("edited by the debugger: {}", text.trim_end()))?;
+                bytes = tamper::frame(&edited);
+                message = edited;
+            }
+            Command::Inject(to, injected) => {
+                let text = String::from_utf8_lossy(&injected);
+                inspector.on_remark(&format!("debugger sends to the {to}: {}", text.trim_end()))?;
+                let framed = tamper::frame(&injected);
+                let result = if to == side {
+                    let reply = tamper.reply_with(framed);
+                    tamper.reply(reply)
+                } else {
+                    tamper.count_sent(1);
+                    tamper.write(w, &framed)
+                };
+                if let Err(e) = result {
+                    return Ok(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Like [`pump`] but the data stays in the kernel.
+#[cfg(target_os = "linux")]
+fn splice_pump(
+    mut inspector: impl Observer,
+    mut r: Incoming,
+    mut w: Outgoing,
+    from: RawFd,
+    to: RawFd,
+    conn: &Connection,
+    side: Side,
+) -> io::Result<()> {
+    let mut splicer = Splicer::new()?;
+
+    loop {
+        let nread = match splicer.fill(from) {
+            Err(e) => {
+                let result = inspector.on_error(false, &explain_error(conn, e));
                 let _ = w.shutdown();
                 return result;
             }
             Ok(0) => {
-                inspector.on_close()?;
+                if let Some(e) = kill_error(conn) {
+                    inspector.on_error(false, &e)?;
+                } else {
+                    inspector.on_close()?;
+                }
                 let _ = w.shutdown();
                 return Ok(());
             }
             Ok(n) => n,
         };
 
-        inspector.on_data(&buffer[..nread])?;
+        conn.count(side, nread);
+        inspector.on_forwarded(nread)?;
 
-        if let Err(e) = w.write_all(&buffer[0..nread]) {
-            inspector.on_error(true, &e)?;
+        if let Err(e) = splicer.drain(to, nread) {
+            inspector.on_error(true, &explain_error(conn, e))?;
             let _ = r.shutdown();
             return Ok(());
         }
     }
 }
 
-fn spawn_worker<N: fmt::Display>(
+/// If the connection was closed by the proxy, errors are most likely
+/// caused by that, so report the reason instead.
+pub fn explain_error(conn: &Connection, err: io::Error) -> io::Error {
+    kill_error(conn).unwrap_or(err)
+}
+
+pub fn kill_error(conn: &Connection) -> Option<io::Error> {
+    let reason = conn.killed()?;
+    let kind = io::ErrorKind::ConnectionAborted;
+    Some(io::Error::new(
+        kind,
+        format!("closed by the proxy: {reason}"),
+    ))
+}
+
+pub fn spawn_worker<N: fmt::Display>(
     name: N,
     f: impl FnOnce() -> io::Result<()> + Send + 'static,
 ) -> thread::JoinHandle<()> {
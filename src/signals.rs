@@ -0,0 +1,50 @@
+use std::sync::mpsc::{self, Receiver};
+use std::{io, mem, ptr, thread};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Interrupt,
+    Terminate,
+    Hangup,
+}
+
+/// Block SIGINT, SIGTERM and SIGHUP and deliver them through a channel
+/// instead. The signal mask is inherited, so this must be called before
+/// any other threads are started.
+pub fn watch() -> io::Result<Receiver<Signal>> {
+    // SAFETY: sigset_t is plain data, initialized by sigemptyset
+    let set = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::sigaddset(&mut set, sig);
+        }
+        set
+    };
+    // SAFETY: set is valid, the old mask is not requested
+    let rc = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || loop {
+            let mut sig = 0;
+            // SAFETY: set is valid and sig is a valid place to store the result
+            if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+                continue;
+            }
+            let signal = match sig {
+                libc::SIGINT => Signal::Interrupt,
+                libc::SIGTERM => Signal::Terminate,
+                libc::SIGHUP => Signal::Hangup,
+                _ => continue,
+            };
+            if sender.send(signal).is_err() {
+                return;
+            }
+        })?;
+    Ok(receiver)
+}
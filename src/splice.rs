@@ -0,0 +1,68 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+
+/// How much to move per call, the default capacity of a pipe.
+const CHUNK: usize = 65536;
+
+/// Moves data from one socket to another using splice(2), through a pipe,
+/// so it never has to be copied into user space.
+pub struct Splicer {
+    pipe_r: OwnedFd,
+    pipe_w: OwnedFd,
+}
+
+impl Splicer {
+    pub fn new() -> io::Result<Splicer> {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for the two file descriptors
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 just created these and nobody else owns them
+        let (pipe_r, pipe_w) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        Ok(Splicer { pipe_r, pipe_w })
+    }
+
+    /// Move data from the socket into the pipe. Returns the number of bytes,
+    /// 0 at end of file. The caller must [`Splicer::drain`] it before filling
+    /// again.
+    pub fn fill(&mut self, from: RawFd) -> io::Result<usize> {
+        splice(from, self.pipe_w.as_raw_fd(), CHUNK)
+    }
+
+    /// Move `n` bytes from the pipe to the socket.
+    pub fn drain(&mut self, to: RawFd, mut n: usize) -> io::Result<()> {
+        while n > 0 {
+            match splice(self.pipe_r.as_raw_fd(), to, n)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                written => n -= written,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    loop {
+        // SAFETY: no offsets are passed, both are file descriptors we hold on to
+        let n = unsafe {
+            libc::splice(
+                from,
+                ptr::null_mut(),
+                to,
+                ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Just enough knowledge of SQL syntax to split a message into statements.
+
+/// One statement from a client message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    /// Comments removed, runs of white space replaced by a single space
+    pub text: String,
+    /// The first keyword, in upper case
+    pub kind: String,
+    /// The keywords and unquoted identifiers, in upper case
+    pub words: Vec<String>,
+}
+
+/// Split the SQL into statements at the semicolons that are not in a string
+/// literal, quoted identifier or comment. Empty statements are left out.
+pub fn statements(sql: &str) -> Vec<Statement> {
+    let mut statements = vec![];
+    let mut current = Builder::default();
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => statements.extend(current.finish()),
+            '\'' | '"' => {
+                // A backslash escapes in string literals but not in identifiers
+                let raw = c == '"' || current.raw_prefix();
+                current.push(c);
+                current.end_word();
+                while let Some(d) = chars.next() {
+                    current.text.push(d);
+                    if d == '\\' && !raw {
+                        current.text.extend(chars.next());
+                    } else if d == c {
+                        if chars.peek() != Some(&c) {
+                            break;
+                        }
+                        current.text.extend(chars.next());
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for d in chars.by_ref() {
+                    if d == '\n' {
+                        break;
+                    }
+                }
+                current.space();
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = ' ';
+                for d in chars.by_ref() {
+                    if prev == '*' && d == '/' {
+                        break;
+                    }
+                    prev = d;
+                }
+                current.space();
+            }
+            c if c.is_whitespace() => current.space(),
+            c => current.push(c),
+        }
+    }
+    statements.extend(current.finish());
+    statements
+}
+
+#[derive(Debug, Default)]
+struct Builder {
+    text: String,
+    words: Vec<String>,
+    word: String,
+}
+
+impl Builder {
+    fn push(&mut self, c: char) {
+        self.text.push(c);
+        if c.is_alphanumeric() || c == '_' {
+            self.word.extend(c.to_uppercase());
+        } else {
+            self.end_word();
+        }
+    }
+
+    fn space(&mut self) {
+        self.end_word();
+        if !self.text.is_empty() && !self.text.ends_with(' ') {
+            self.text.push(' ');
+        }
+    }
+
+    fn end_word(&mut self) {
+        if !self.word.is_empty() {
+            self.words.push(std::mem::take(&mut self.word));
+        }
+    }
+
+    /// Whether the string literal about to start is a raw string, r'...'.
+    fn raw_prefix(&self) -> bool {
+        self.word == "R"
+    }
+
+    fn finish(&mut self) -> Option<Statement> {
+        self.end_word();
+        let text = self.text.trim().to_string();
+        let words = std::mem::take(&mut self.words);
+        self.text.clear();
+        if text.is_empty() {
+            return None;
+        }
+        Some(Statement {
+            text,
+            kind: words.first().cloned().unwrap_or_default(),
+            words,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(sql: &str) -> Vec<String> {
+        statements(sql).into_iter().map(|s| s.kind).collect()
+    }
+
+    #[test]
+    fn test_statements() {
+        assert_eq!(kinds("select 1; delete from t;;"), ["SELECT", "DELETE"]);
+        assert_eq!(kinds("select ';delete' from t"), ["SELECT"]);
+        assert_eq!(
+            kinds("select 'it''s; fine'; drop table t"),
+            ["SELECT", "DROP"]
+        );
+        assert_eq!(kinds(r"select 'a\'; drop table t'"), ["SELECT"]);
+        assert_eq!(kinds(r"select r'a\'; drop table t"), ["SELECT", "DROP"]);
+        assert_eq!(kinds(r#"select "a;b" from t"#), ["SELECT"]);
+        assert_eq!(
+            kinds("select 1 -- ; drop table t\n; commit"),
+            ["SELECT", "COMMIT"]
+        );
+        assert_eq!(kinds("/* ; drop table t */ select 1"), ["SELECT"]);
+        assert!(statements(" ; -- nothing\n").is_empty());
+    }
+
+    #[test]
+    fn test_statement_text() {
+        let statement = &statements("SELECT  *\n/* all */ from\tt")[0];
+        assert_eq!(statement.text, "SELECT * from t");
+        assert_eq!(statement.words, ["SELECT", "FROM", "T"]);
+    }
+}
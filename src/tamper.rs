@@ -0,0 +1,1524 @@
+//! Things the proxy can do to the data on its way instead of faithfully
+//! forwarding it, to see how clients and servers cope.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use regex::{NoExpand, Regex};
+
+use crate::debugger::{Command, Debugger};
+use crate::firewall::Firewall;
+use crate::formatter::Side;
+use crate::observers::{Blocks, Frame};
+use crate::proxy::BLOCKSIZE;
+
+/// Client messages longer than this are not fully kept for matching.
+const MAX_QUERY: usize = 1 << 20;
+
+#[derive(Debug, Default)]
+pub struct TamperConfig {
+    pub delay: Option<DelayRule>,
+    /// Client to server
+    pub upload: Option<RateLimit>,
+    /// Server to client
+    pub download: Option<RateLimit>,
+    pub chaos: Option<ChaosRule>,
+    /// Only applies to data from the server
+    pub corrupt: Option<Corruption>,
+    pub inject: Option<InjectRule>,
+    /// Applied in order
+    pub rewrite: Vec<RewriteRule>,
+    /// Checks the SQL after rewriting
+    pub firewall: Option<Firewall>,
+    /// Ask what to do with every message
+    pub debugger: Option<Debugger>,
+}
+
+impl TamperConfig {
+    pub fn is_empty(&self) -> bool {
+        self.delay.is_none()
+            && self.upload.is_none()
+            && self.download.is_none()
+            && self.chaos.is_none()
+            && self.corrupt.is_none()
+            && self.inject.is_none()
+            && self.rewrite.is_empty()
+            && self.firewall.is_none()
+            && self.debugger.is_none()
+    }
+
+    fn rate_limit(&self, side: Side) -> Option<&RateLimit> {
+        match side {
+            Side::Client => self.upload.as_ref(),
+            Side::Server => self.download.as_ref(),
+        }
+    }
+
+    /// Whether any of the rules needs to see the text of the client messages.
+    fn needs_queries(&self) -> bool {
+        self.delay.as_ref().is_some_and(|d| d.matching.is_some())
+            || self.chaos.as_ref().is_some_and(|c| c.query.is_some())
+            || self.holds_queries()
+    }
+
+    /// Whether client messages must be held back until it's been decided
+    /// what to do with them.
+    pub fn holds_queries(&self) -> bool {
+        self.inject.is_some() || !self.rewrite.is_empty() || self.firewall.is_some()
+    }
+}
+
+/// Hold back messages before forwarding them.
+#[derive(Debug, Default)]
+pub struct DelayRule {
+    pub fixed: Duration,
+    /// A random extra delay up to this much is added
+    pub jitter: Duration,
+    /// Only delay messages from this side, default both
+    pub side: Option<Side>,
+    /// Only delay client messages that match, and the server's replies to them
+    pub matching: Option<Regex>,
+}
+
+impl DelayRule {
+    fn pick(&self) -> Duration {
+        let jitter = self.jitter.as_micros() as u64;
+        self.fixed + Duration::from_micros(fastrand::u64(0..=jitter))
+    }
+}
+
+/// Break the connection in some way when all of the given triggers have fired.
+#[derive(Debug)]
+pub struct ChaosRule {
+    pub action: ChaosAction,
+    /// The direction to count in and to act on
+    pub side: Side,
+    pub after_bytes: Option<u64>,
+    pub after_messages: Option<u64>,
+    /// Only in the server's reply to client messages that match.
+    /// Bytes and messages are counted from the start of the reply.
+    pub query: Option<Regex>,
+    /// Chance for each message to trigger the action
+    pub probability: Option<f64>,
+}
+
+impl Default for ChaosRule {
+    fn default() -> Self {
+        ChaosRule {
+            action: ChaosAction::Close,
+            side: Side::Server,
+            after_bytes: None,
+            after_messages: None,
+            query: None,
+            probability: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChaosAction {
+    /// Close both sides of the connection
+    Close,
+    /// Reset both sides of the connection, TCP RST where possible
+    Reset,
+    /// Forward half of a block body and then close the connection
+    Truncate,
+    /// Stop forwarding in this direction but leave the connection open
+    Stall,
+}
+
+/// Damage the block structure of the data sent by the server.
+#[derive(Debug)]
+pub struct Corruption {
+    pub modes: Vec<CorruptMode>,
+    /// Chance for each block to be damaged
+    pub probability: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptMode {
+    /// Block header claims a different length than the actual body
+    Length,
+    /// Clear the last-block flag, the message never ends
+    NoLast,
+    /// Flip a bit in the body
+    BitFlip,
+    /// Drop the block that ends the message, so the client never gets its prompt
+    NoPrompt,
+}
+
+/// Answer matching client messages with an error instead of forwarding them.
+#[derive(Debug)]
+pub struct InjectRule {
+    pub matching: Regex,
+    pub sqlstate: String,
+    pub message: String,
+}
+
+impl InjectRule {
+    fn response(&self) -> String {
+        format!("!{}!{}\n", self.sqlstate, self.message)
+    }
+}
+
+/// Substitute text in the SQL of client messages.
+#[derive(Debug)]
+pub struct RewriteRule {
+    pub pattern: Regex,
+    pub replacement: String,
+    /// No $1 expansion in the replacement
+    pub literal: bool,
+}
+
+impl RewriteRule {
+    pub fn literal(text: &str, replacement: String) -> RewriteRule {
+        let pattern = Regex::new(&regex::escape(text)).expect("escaped text is a valid regex");
+        RewriteRule {
+            pattern,
+            replacement,
+            literal: true,
+        }
+    }
+
+    fn apply<'a>(&self, sql: &'a str) -> Cow<'a, str> {
+        if self.literal {
+            self.pattern.replace_all(sql, NoExpand(&self.replacement))
+        } else {
+            self.pattern.replace_all(sql, self.replacement.as_str())
+        }
+    }
+}
+
+/// Limit the number of bytes per second forwarded in one direction.
+#[derive(Debug)]
+pub struct RateLimit {
+    rate: u64,
+    /// The bucket used by all connections, if it is shared
+    shared: Option<Arc<Mutex<Bucket>>>,
+}
+
+impl RateLimit {
+    pub fn new(rate: u64, shared: bool) -> RateLimit {
+        let shared = shared.then(|| Arc::new(Mutex::new(Bucket::new(rate))));
+        RateLimit { rate, shared }
+    }
+
+    fn bucket(&self) -> Arc<Mutex<Bucket>> {
+        match &self.shared {
+            Some(bucket) => Arc::clone(bucket),
+            None => Arc::new(Mutex::new(Bucket::new(self.rate))),
+        }
+    }
+}
+
+/// Token bucket holding at most a tenth of a second worth of bytes.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    /// Negative if bytes have been sent on credit
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        let rate = rate.max(1) as f64;
+        let capacity = (rate / 10.0).max(1.0);
+        Bucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Largest amount worth sending at once.
+    fn chunk(&self) -> usize {
+        self.capacity as usize
+    }
+
+    /// Take `n` bytes from the bucket, returns how long to wait before
+    /// sending them.
+    fn take(&mut self, n: usize) -> Duration {
+        let now = Instant::now();
+        let refill = (now - self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.capacity) - n as f64;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// What to do with the data that has been read.
+#[derive(Debug)]
+pub enum Action {
+    /// Forward this part of the data
+    Forward(Range<usize>),
+    /// Send these bytes
+    Send(Vec<u8>),
+    Delay(Duration),
+    /// Tell the observer what we're doing
+    Remark(String),
+    /// Close the connection for the given reason
+    Close(String),
+    /// Reset the connection for the given reason
+    Reset(String),
+    /// Send these bytes back to where the data came from
+    Reply(Reply),
+    /// Ask the debugger what to do with this message, the bytes as
+    /// received and the payload
+    Break(Vec<u8>, Vec<u8>),
+}
+
+/// Bytes sent back to where the data came from, written by the other
+/// direction between two messages.
+#[derive(Debug)]
+pub struct Reply {
+    data: Vec<u8>,
+    /// Sent to the client only after the server has answered this many
+    /// client messages, so the replies come in the order of the queries
+    after: u64,
+}
+
+/// What a pump that receives replies waits for, see [`Tamper::take_inputs`].
+#[derive(Debug)]
+pub enum Input {
+    /// Data read from the socket, empty at the end of the stream
+    Read(io::Result<Vec<u8>>),
+    /// Sent by the other direction
+    Reply(Reply),
+}
+
+/// Create the tampering state for both directions of a new connection,
+/// or None if there is nothing to do.
+pub fn for_connection(config: &Arc<TamperConfig>) -> Option<(Tamper, Tamper)> {
+    if config.is_empty() {
+        return None;
+    }
+    let exchange = Arc::new(Mutex::new(Exchange::default()));
+    let for_client = mpsc::channel();
+    let for_server = mpsc::channel();
+    let make = |side, reply_to, inputs| Tamper {
+        side,
+        bucket: config.rate_limit(side).map(RateLimit::bucket),
+        config: Arc::clone(config),
+        exchange: Arc::clone(&exchange),
+        blocks: Blocks::default(),
+        message: vec![],
+        message_start: 0,
+        offset: 0,
+        chaos: Chaos::default(),
+        damage: Damage::default(),
+        held: None,
+        hold_from: 0,
+        oversized: false,
+        empty: false,
+        reply_to,
+        inputs: Some(inputs),
+        replies: VecDeque::new(),
+        answered: 0,
+    };
+    let (to_client, to_server) = (for_client.0.clone(), for_server.0.clone());
+    Some((
+        make(Side::Client, to_client, for_server),
+        make(Side::Server, to_server, for_client),
+    ))
+}
+
+/// What one direction needs to know about the other.
+#[derive(Debug, Default)]
+struct Exchange {
+    /// The last client message matched the delay rule, so delay the reply
+    delay_reply: bool,
+    /// The last client message matched the chaos rule
+    chaos_reply: bool,
+    /// The server has sent its first prompt, so the client is no longer
+    /// sending login messages
+    logged_in: bool,
+    /// Client messages sent to the server, each gets one message in answer
+    sent: u64,
+}
+
+/// What is being done to the current block.
+#[derive(Debug, Default)]
+struct Damage {
+    flip_bit: bool,
+    drop_body: bool,
+}
+
+/// Progress of the chaos rule in one direction.
+#[derive(Debug, Default)]
+struct Chaos {
+    /// Stream offset from which bytes and messages are counted
+    armed_at: Option<u64>,
+    messages: u64,
+    /// Whether the current message won the lottery
+    lucky: bool,
+    fired: bool,
+    /// Waiting for a block body to cut in half
+    truncating: bool,
+    stalled: bool,
+}
+
+/// Tampering state for one direction of a connection.
+pub struct Tamper {
+    side: Side,
+    config: Arc<TamperConfig>,
+    bucket: Option<Arc<Mutex<Bucket>>>,
+    exchange: Arc<Mutex<Exchange>>,
+    blocks: Blocks,
+    /// Payload of the current message, if the rules need it
+    message: Vec<u8>,
+    /// Where the current message started in the data being processed
+    message_start: usize,
+    /// Number of bytes processed before the current data
+    offset: u64,
+    chaos: Chaos,
+    damage: Damage,
+    /// The message received so far, while holding it back
+    held: Option<Vec<u8>>,
+    /// Where the held back part of the data being processed starts
+    hold_from: usize,
+    /// The held back message is too big for the firewall to check, so
+    /// the rest of it is dropped and the message refused
+    oversized: bool,
+    /// No payload has been seen yet in the current message
+    empty: bool,
+    /// Where to send data back to where it came from, the other direction
+    /// writes it between two messages so each socket has a single writer
+    reply_to: Sender<Input>,
+    /// Until the pump takes them, see [`Tamper::take_inputs`]
+    inputs: Option<(Sender<Input>, Receiver<Input>)>,
+    /// Replies from the other direction waiting for their turn
+    replies: VecDeque<Reply>,
+    /// Server messages that answered a client message
+    answered: u64,
+}
+
+impl Tamper {
+    /// Decide what to do with the data that has just been read.
+    pub fn process(&mut self, data: &[u8]) -> Vec<Action> {
+        let mut plan = Plan::default();
+        if self.chaos.stalled {
+            return vec![];
+        }
+        if self.chaos_side()
+            && self.chaos.armed_at.is_none()
+            && self
+                .config
+                .chaos
+                .as_ref()
+                .is_some_and(|c| c.query.is_none())
+        {
+            self.chaos.armed_at = Some(0);
+        }
+        self.message_start = 0;
+        for (pos, frame) in self.blocks.feed(data) {
+            if self.chaos_bytes_reached(pos, &mut plan) {
+                return plan.actions;
+            }
+            match frame {
+                Frame::Start => {
+                    self.message.clear();
+                    self.empty = true;
+                    self.message_start = pos;
+                    if let Some(delay) = self.delay_at_start() {
+                        plan.delay(pos, delay);
+                    }
+                    if self.chaos_side() {
+                        self.chaos_message_start(pos);
+                    }
+                    if self.holds() {
+                        self.held = Some(vec![]);
+                        self.hold_from = pos;
+                        self.oversized = false;
+                    }
+                }
+                Frame::Header(header) => {
+                    if self.side == Side::Server {
+                        self.corrupt_header(pos, header, &mut plan);
+                    }
+                }
+                Frame::Payload(ref range) => {
+                    self.empty = false;
+                    if self.damage.drop_body {
+                        plan.skip(range.clone());
+                    } else if self.damage.flip_bit {
+                        self.damage.flip_bit = false;
+                        let i = fastrand::usize(range.clone());
+                        let bit = 1 << fastrand::u8(0..8);
+                        plan.replace(i..i + 1, vec![data[i] ^ bit]);
+                        plan.corruption(format!(
+                            "proxy corrupts block body: flips bit {bit:#04x} of a byte"
+                        ));
+                    }
+                    if self.needs_message() && self.message.len() < MAX_QUERY {
+                        self.message.extend_from_slice(&data[range.clone()]);
+                    }
+                }
+                Frame::BlockEnd(_) => continue,
+                Frame::End => {
+                    let held = self.hold(data, pos, &mut plan);
+                    if self.side == Side::Client {
+                        self.client_message_done(&mut plan);
+                    } else {
+                        self.server_message_done();
+                    }
+                    match held {
+                        Some(held) if self.side == Side::Client => self.decide(held, &mut plan),
+                        Some(held) => self.forward(held, self.message.clone(), &mut plan),
+                        None if self.side == Side::Client => self.count_sent(1),
+                        None => {}
+                    }
+                    if self.chaos_side() && self.chaos.armed_at.is_some() {
+                        self.chaos.messages += 1;
+                    }
+                    let replies = self.due_replies();
+                    if !replies.is_empty() {
+                        plan.forward_until(pos);
+                        plan.actions.extend(replies.into_iter().map(Action::Send));
+                    }
+                }
+            }
+            if self.chaos_side() && self.chaos_check(pos, &frame, &mut plan) {
+                return plan.actions;
+            }
+        }
+        if self.chaos_bytes_reached(data.len(), &mut plan) {
+            return plan.actions;
+        }
+        if let Some(held) = self.hold(data, data.len(), &mut plan) {
+            if held.len() <= MAX_QUERY {
+                self.held = Some(held);
+            } else if self.side == Side::Client && self.config.firewall.is_some() {
+                // Too big to check, keep dropping it until it ends
+                self.oversized = true;
+                self.held = Some(vec![]);
+            } else {
+                // Too big to judge, let it through
+                plan.actions.push(Action::Send(held));
+            }
+            self.hold_from = 0;
+        }
+        self.offset += data.len() as u64;
+        plan.finish(data.len())
+    }
+
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    /// What to do with a message that has been held for the debugger.
+    pub fn ask(&self, message: &[u8]) -> Command {
+        match &self.config.debugger {
+            Some(debugger) => debugger.ask(self.side, message),
+            None => Command::Forward,
+        }
+    }
+
+    /// Data to send back to where the data comes from. To the client it
+    /// goes after the answers to the messages sent to the server so far.
+    pub fn reply_with(&self, data: Vec<u8>) -> Reply {
+        let after = match self.side {
+            Side::Client => self.exchange.lock().unwrap().sent,
+            Side::Server => 0,
+        };
+        Reply { data, after }
+    }
+
+    /// Send data back to where the data comes from.
+    pub fn reply(&mut self, reply: Reply) -> io::Result<()> {
+        self.reply_to.send(Input::Reply(reply)).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!(
+                    "cannot send to the {}, the connection is closing",
+                    self.side
+                ),
+            )
+        })
+    }
+
+    /// Whether the other direction may send replies for this one to write.
+    pub fn receives_replies(&self) -> bool {
+        self.config.debugger.is_some() || (self.side == Side::Server && self.config.holds_queries())
+    }
+
+    /// If this direction receives replies, the channel to wait on for both
+    /// them and the data read from the socket, and a sender for the latter.
+    pub fn take_inputs(&mut self) -> Option<(Sender<Input>, Receiver<Input>)> {
+        let inputs = self.inputs.take();
+        inputs.filter(|_| self.receives_replies())
+    }
+
+    /// Keep a reply from the other direction until it's its turn, see
+    /// [`Tamper::replies`].
+    pub fn queue(&mut self, reply: Reply) {
+        self.replies.push_back(reply);
+    }
+
+    /// The queued replies that can be written now without ending up in
+    /// the middle of a message or before an earlier answer.
+    pub fn replies(&mut self) -> Vec<Vec<u8>> {
+        if self.blocks.in_message() {
+            return vec![];
+        }
+        self.due_replies()
+    }
+
+    fn due_replies(&mut self) -> Vec<Vec<u8>> {
+        let mut due = vec![];
+        while let Some(reply) = self.replies.pop_front() {
+            if reply.after > self.answered {
+                self.replies.push_front(reply);
+                break;
+            }
+            due.push(reply.data);
+        }
+        due
+    }
+
+    /// The client pump has sent messages to the server or, with a
+    /// negative count, has dropped messages it was expected to send.
+    pub fn count_sent(&self, n: i64) {
+        if self.side == Side::Client {
+            let sent = &mut self.exchange.lock().unwrap().sent;
+            *sent = sent.saturating_add_signed(n);
+        }
+    }
+
+    fn server_message_done(&mut self) {
+        let mut exchange = self.exchange.lock().unwrap();
+        if self.empty {
+            exchange.logged_in = true;
+        }
+        // Anything before the first client message, the challenge, is not
+        // an answer
+        if self.answered < exchange.sent {
+            self.answered += 1;
+        }
+    }
+
+    /// Whether messages must be held back until it's been decided what to
+    /// do with them.
+    fn holds(&self) -> bool {
+        self.config
+            .debugger
+            .as_ref()
+            .is_some_and(Debugger::stepping)
+            || (self.side == Side::Client && self.config.holds_queries())
+    }
+
+    fn needs_message(&self) -> bool {
+        self.config.debugger.is_some() || (self.side == Side::Client && self.config.needs_queries())
+    }
+
+    /// Forward a complete message that has been held back, unless the
+    /// debugger wants to have a look at it first.
+    fn forward(&mut self, held: Vec<u8>, message: Vec<u8>, plan: &mut Plan) {
+        if held.len() <= MAX_QUERY
+            && self
+                .config
+                .debugger
+                .as_ref()
+                .is_some_and(Debugger::stepping)
+        {
+            plan.actions.push(Action::Break(held, message));
+        } else {
+            plan.actions.push(Action::Send(held));
+        }
+    }
+
+    /// Take the part of the held back message up to `pos` out of the
+    /// data and return the whole thing, if we're holding back.
+    fn hold(&mut self, data: &[u8], pos: usize, plan: &mut Plan) -> Option<Vec<u8>> {
+        let mut held = self.held.take()?;
+        if !self.oversized {
+            held.extend_from_slice(&data[self.hold_from..pos]);
+        }
+        plan.skip(self.hold_from..pos);
+        Some(held)
+    }
+
+    /// A complete client message has been held back, forward it or not.
+    fn decide(&mut self, held: Vec<u8>, plan: &mut Plan) {
+        let oversized = self.oversized || held.len() > MAX_QUERY;
+        self.oversized = false;
+        if oversized && self.config.firewall.is_some() {
+            let reason = format!("message is larger than {MAX_QUERY} bytes");
+            plan.remark(format!("firewall blocks this message: {reason}"));
+            let response = format!("!42000!blocked by the proxy: {reason}\n");
+            let reply = self.reply_with(frame(response.as_bytes()));
+            plan.actions.push(Action::Reply(reply));
+            return;
+        }
+        if held.len() > MAX_QUERY {
+            // self.message may be incomplete
+            self.count_sent(1);
+            plan.actions.push(Action::Send(held));
+            return;
+        }
+        let query = query_text(&self.message);
+        if let Some(rule) = self
+            .config
+            .inject
+            .as_ref()
+            .filter(|r| r.matching.is_match(&query))
+        {
+            let response = rule.response();
+            plan.remark(format!(
+                "proxy answers instead of the server: {}",
+                response.trim_end()
+            ));
+            let reply = self.reply_with(frame(response.as_bytes()));
+            plan.actions.push(Action::Reply(reply));
+            return;
+        }
+        let rewritten = self.rewrite();
+        if let Some(sql) = &rewritten {
+            plan.remark(format!("proxy rewrote this message to: {sql}"));
+        }
+        if let Some(firewall) = &self.config.firewall {
+            let verdict = match self.message.first() {
+                Some(b's' | b'S') => {
+                    let sql = rewritten
+                        .as_deref()
+                        .map_or_else(|| query_text(&self.message), Cow::from);
+                    Some(firewall.check(&sql))
+                }
+                Some(b'X') => {
+                    Some(firewall.check_command(&String::from_utf8_lossy(&self.message[1..])))
+                }
+                // Anything but SQL and commands after login is refused, the
+                // firewall cannot tell what it does
+                first if self.exchange.lock().unwrap().logged_in => Some(Err(match first {
+                    Some(&b) => format!("unknown message type {:?}", b as char),
+                    None => "empty message".to_string(),
+                })),
+                _ => None,
+            };
+            match verdict {
+                Some(Err(reason)) => {
+                    plan.remark(format!("firewall blocks this message: {reason}"));
+                    let response = format!("!42000!blocked by the proxy: {reason}\n");
+                    let reply = self.reply_with(frame(response.as_bytes()));
+                    plan.actions.push(Action::Reply(reply));
+                    return;
+                }
+                Some(Ok(())) => plan.remark("firewall allows this message".to_string()),
+                None => {}
+            }
+        }
+        // Counted before it is sent, if the debugger drops it see
+        // Tamper::count_sent
+        self.count_sent(1);
+        match rewritten {
+            Some(sql) => {
+                // Keep the 's' or 'S'
+                let message = [&self.message[..1], sql.as_bytes()].concat();
+                self.forward(frame(&message), message, plan);
+            }
+            None => self.forward(held, self.message.clone(), plan),
+        }
+    }
+
+    /// The SQL of the current message after applying the rewrite rules,
+    /// if any of them changed it.
+    fn rewrite(&self) -> Option<String> {
+        let (_, sql) = split_sql(&self.message)?;
+        let mut sql = std::str::from_utf8(sql).ok()?.to_string();
+        let mut changed = false;
+        for rule in &self.config.rewrite {
+            let rewritten = match rule.apply(&sql) {
+                Cow::Owned(rewritten) => rewritten,
+                Cow::Borrowed(_) => continue,
+            };
+            sql = rewritten;
+            changed = true;
+        }
+        changed.then_some(sql)
+    }
+
+    /// Write the data, no faster than the rate limit allows.
+    pub fn write(&mut self, w: &mut impl Write, data: &[u8]) -> io::Result<()> {
+        let Some(bucket) = &self.bucket else {
+            return w.write_all(data);
+        };
+        let chunk = bucket.lock().unwrap().chunk();
+        for piece in data.chunks(chunk) {
+            let wait = bucket.lock().unwrap().take(piece.len());
+            thread::sleep(wait);
+            w.write_all(piece)?;
+        }
+        Ok(())
+    }
+
+    fn delay_at_start(&self) -> Option<Duration> {
+        let rule = self.config.delay.as_ref()?;
+        if rule.side.is_some_and(|s| s != self.side) {
+            return None;
+        }
+        match (&rule.matching, self.side) {
+            (None, _) => Some(rule.pick()),
+            // Decided when the message is complete
+            (Some(_), Side::Client) => None,
+            (Some(_), Side::Server) => {
+                let delay_reply = &mut self.exchange.lock().unwrap().delay_reply;
+                if *delay_reply {
+                    *delay_reply = false;
+                    Some(rule.pick())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Called right after a block header, at `pos`.
+    fn corrupt_header(&mut self, pos: usize, header: u16, plan: &mut Plan) {
+        self.damage = Damage::default();
+        let Some(corrupt) = &self.config.corrupt else {
+            return;
+        };
+        // Headers split over two reads are left alone
+        if pos < 2 + plan.done || fastrand::f64() >= corrupt.probability {
+            return;
+        }
+        let header_range = pos - 2..pos;
+        let len = header / 2;
+        let last = header & 1 != 0;
+        match corrupt.modes[fastrand::usize(..corrupt.modes.len())] {
+            CorruptMode::Length => {
+                let wrong = loop {
+                    let delta = fastrand::i32(-16..=16);
+                    let wrong = (len as i32 + delta).clamp(0, 0x7FFF) as u16;
+                    if wrong != len {
+                        break wrong;
+                    }
+                };
+                plan.replace(
+                    header_range,
+                    ((wrong << 1) | last as u16).to_le_bytes().to_vec(),
+                );
+                plan.corruption(format!(
+                    "proxy corrupts block header: length {len} becomes {wrong}"
+                ));
+            }
+            CorruptMode::NoLast if last => {
+                plan.replace(header_range, (header & !1).to_le_bytes().to_vec());
+                plan.corruption(
+                    "proxy corrupts block header: clears the last-block flag".to_string(),
+                );
+            }
+            CorruptMode::BitFlip if len > 0 => self.damage.flip_bit = true,
+            CorruptMode::NoPrompt if last => {
+                plan.skip(header_range);
+                self.damage.drop_body = true;
+                plan.corruption("proxy drops the block that ends the message".to_string());
+            }
+            _ => {}
+        }
+    }
+
+    fn chaos_side(&self) -> bool {
+        self.config
+            .chaos
+            .as_ref()
+            .is_some_and(|c| c.side == self.side)
+    }
+
+    fn chaos_message_start(&mut self, pos: usize) {
+        let rule = self.config.chaos.as_ref().unwrap();
+        if rule.query.is_some() && !self.chaos.fired {
+            let chaos_reply = &mut self.exchange.lock().unwrap().chaos_reply;
+            if *chaos_reply {
+                // A new reply, start counting again
+                *chaos_reply = false;
+                self.chaos.armed_at = Some(self.offset + pos as u64);
+                self.chaos.messages = 0;
+            }
+        }
+        self.chaos.lucky = rule.probability.is_some_and(|p| fastrand::f64() < p);
+    }
+
+    /// Fire if the byte count is reached somewhere before `pos`
+    /// while all other triggers hold.
+    fn chaos_bytes_reached(&mut self, pos: usize, plan: &mut Plan) -> bool {
+        let Some(rule) = &self.config.chaos else {
+            return false;
+        };
+        let (Some(armed_at), Some(n)) = (self.chaos.armed_at, rule.after_bytes) else {
+            return false;
+        };
+        if rule.side != self.side || self.chaos.fired {
+            return false;
+        }
+        let mark = armed_at + n;
+        if mark < self.offset + plan.done as u64 || mark > self.offset + pos as u64 {
+            return false;
+        }
+        let mark = (mark - self.offset) as usize;
+        self.chaos_triggered(mark) && self.chaos_fire(mark, plan)
+    }
+
+    /// Fire if all triggers hold at a block or message boundary, or
+    /// cut a block in half if we're truncating.
+    fn chaos_check(&mut self, pos: usize, frame: &Frame, plan: &mut Plan) -> bool {
+        if self.chaos.truncating {
+            if let Frame::Payload(range) = frame {
+                if range.len() >= 2 && range.start >= plan.done {
+                    let cut = range.start + range.len() / 2;
+                    plan.forward_until(cut);
+                    plan.actions
+                        .push(Action::Close("chaos: truncated a block".to_string()));
+                    return true;
+                }
+            }
+            return false;
+        }
+        if self.chaos.fired || !self.chaos_triggered(pos) {
+            return false;
+        }
+        self.chaos_fire(pos, plan)
+    }
+
+    /// Whether all triggers hold at the given position in the current data.
+    fn chaos_triggered(&self, pos: usize) -> bool {
+        let rule = self.config.chaos.as_ref().unwrap();
+        let Some(armed_at) = self.chaos.armed_at else {
+            return false;
+        };
+        let bytes = self.offset + pos as u64 - armed_at;
+        rule.after_bytes.is_none_or(|n| bytes >= n)
+            && rule.after_messages.is_none_or(|n| self.chaos.messages >= n)
+            && (rule.probability.is_none() || self.chaos.lucky)
+    }
+
+    /// Carry out the chaos action at the given position. Returns whether
+    /// the rest of the data should be dropped.
+    fn chaos_fire(&mut self, pos: usize, plan: &mut Plan) -> bool {
+        let rule = self.config.chaos.as_ref().unwrap();
+        self.chaos.fired = true;
+        plan.forward_until(pos);
+        match rule.action {
+            ChaosAction::Close => {
+                plan.actions
+                    .push(Action::Close("chaos: closing the connection".to_string()));
+                true
+            }
+            ChaosAction::Reset => {
+                plan.actions
+                    .push(Action::Reset("chaos: resetting the connection".to_string()));
+                true
+            }
+            ChaosAction::Truncate => {
+                plan.actions.push(Action::Remark(
+                    "proxy chaos: truncating the next block".to_string(),
+                ));
+                self.chaos.truncating = true;
+                false
+            }
+            ChaosAction::Stall => {
+                plan.actions.push(Action::Remark(
+                    "proxy chaos: stops forwarding from here on".to_string(),
+                ));
+                self.chaos.stalled = true;
+                true
+            }
+        }
+    }
+
+    fn client_message_done(&mut self, plan: &mut Plan) {
+        if let Some(matching) = self.config.chaos.as_ref().and_then(|c| c.query.as_ref()) {
+            let matched = matching.is_match(&query_text(&self.message));
+            self.exchange.lock().unwrap().chaos_reply = matched;
+        }
+        let Some(rule) = &self.config.delay else {
+            return;
+        };
+        let Some(matching) = &rule.matching else {
+            return;
+        };
+        let matched = matching.is_match(&query_text(&self.message));
+        if matched && rule.side != Some(Side::Server) {
+            // Hold back the end of the message, as far as it's still here
+            plan.delay(self.message_start, rule.pick());
+        }
+        self.exchange.lock().unwrap().delay_reply = matched && rule.side != Some(Side::Client);
+    }
+}
+
+/// Builds the list of actions for a piece of data.
+#[derive(Default)]
+struct Plan {
+    actions: Vec<Action>,
+    /// Everything before this has been taken care of
+    done: usize,
+}
+
+impl Plan {
+    fn delay(&mut self, pos: usize, delay: Duration) {
+        self.forward_until(pos);
+        let ms = delay.as_secs_f64() * 1000.0;
+        self.actions.push(Action::Remark(format!(
+            "proxy delays this message by {ms:.0}ms"
+        )));
+        self.actions.push(Action::Delay(delay));
+    }
+
+    fn remark(&mut self, message: String) {
+        self.actions.push(Action::Remark(message));
+    }
+
+    /// Observers see the data before it is damaged, say so.
+    fn corruption(&mut self, message: String) {
+        self.remark(format!("{message}, the block is shown as received"));
+    }
+
+    /// Send something else instead of this part of the data.
+    fn replace(&mut self, range: Range<usize>, replacement: Vec<u8>) {
+        self.skip(range);
+        self.actions.push(Action::Send(replacement));
+    }
+
+    /// Do not forward this part of the data.
+    fn skip(&mut self, range: Range<usize>) {
+        self.forward_until(range.start);
+        self.done = range.end;
+    }
+
+    fn forward_until(&mut self, pos: usize) {
+        if pos > self.done {
+            self.actions.push(Action::Forward(self.done..pos));
+            self.done = pos;
+        }
+    }
+
+    fn finish(mut self, len: usize) -> Vec<Action> {
+        self.forward_until(len);
+        self.actions
+    }
+}
+
+/// Split a message into blocks.
+pub fn frame(message: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    let mut chunks = message.chunks(BLOCKSIZE).peekable();
+    if chunks.peek().is_none() {
+        return vec![1, 0];
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let header = ((chunk.len() as u16) << 1) | last as u16;
+        framed.extend_from_slice(&header.to_le_bytes());
+        framed.extend_from_slice(chunk);
+    }
+    framed
+}
+
+/// The text of a client message. For SQL, this is without the leading 's'.
+pub fn query_text(message: &[u8]) -> Cow<'_, str> {
+    let sql = split_sql(message).map_or(message, |(_, sql)| sql);
+    String::from_utf8_lossy(sql)
+}
+
+/// The leading 's' or 'S' and the SQL, if the client message is SQL.
+fn split_sql(message: &[u8]) -> Option<(u8, &[u8])> {
+    match message.split_first() {
+        Some((&prefix @ (b's' | b'S'), sql)) => Some((prefix, sql)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The block headers of a framed message.
+    fn headers(framed: &[u8]) -> Vec<u16> {
+        let mut headers = vec![];
+        let mut pos = 0;
+        while pos < framed.len() {
+            let header = u16::from_le_bytes([framed[pos], framed[pos + 1]]);
+            headers.push(header);
+            pos += 2 + (header >> 1) as usize;
+        }
+        assert_eq!(pos, framed.len());
+        headers
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn tampers(config: TamperConfig) -> (Tamper, Tamper) {
+        for_connection(&Arc::new(config)).unwrap()
+    }
+
+    /// The actions in short, without the remarks.
+    fn summary(actions: &[Action]) -> Vec<String> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Forward(range) => Some(format!("forward {range:?}")),
+                Action::Send(data) => Some(format!("send {}", data.len())),
+                Action::Delay(delay) => Some(format!("delay {delay:?}")),
+                Action::Remark(_) => None,
+                Action::Close(_) => Some("close".to_string()),
+                Action::Reset(_) => Some("reset".to_string()),
+                Action::Reply(reply) => Some(format!("reply {}", reply.data.len())),
+                Action::Break(..) => Some("break".to_string()),
+            })
+            .collect()
+    }
+
+    /// The messages in a stream, as [`Blocks::feed`] finds them.
+    fn unframe(stream: &[u8]) -> Vec<Vec<u8>> {
+        let mut blocks = Blocks::default();
+        let mut messages = vec![];
+        let mut message = vec![];
+        for (_, frame) in blocks.feed(stream) {
+            match frame {
+                Frame::Payload(range) => message.extend_from_slice(&stream[range]),
+                Frame::End => messages.push(std::mem::take(&mut message)),
+                Frame::Start | Frame::Header(_) | Frame::BlockEnd(_) => {}
+            }
+        }
+        assert!(!blocks.in_message());
+        messages
+    }
+
+    #[test]
+    fn test_frame() {
+        let full = BLOCKSIZE as u16;
+        assert_eq!(frame(b""), [1, 0]);
+        assert_eq!(frame(b"ab"), [5, 0, b'a', b'b']);
+        // Only the last block has the last-block bit
+        assert_eq!(headers(&frame(&[b'x'; BLOCKSIZE])), [full << 1 | 1]);
+        assert_eq!(headers(&frame(&[b'x'; BLOCKSIZE + 1])), [full << 1, 3]);
+        assert_eq!(
+            headers(&frame(&[b'x'; 2 * BLOCKSIZE])),
+            [full << 1, full << 1 | 1]
+        );
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        let messages = vec![
+            b"sselect 1;".to_vec(),
+            vec![],
+            vec![b'x'; BLOCKSIZE],
+            vec![b'y'; BLOCKSIZE + 1],
+            vec![b'z'; 3 * BLOCKSIZE - 1],
+        ];
+        let stream: Vec<u8> = messages.iter().flat_map(|m| frame(m)).collect();
+        assert_eq!(unframe(&stream), messages);
+    }
+
+    #[test]
+    fn test_delay() {
+        let rule = DelayRule {
+            fixed: ms(10),
+            ..DelayRule::default()
+        };
+        let (mut client, mut server) = tampers(TamperConfig {
+            delay: Some(rule),
+            ..TamperConfig::default()
+        });
+        // Every message in both directions, at its start
+        let data = [frame(b"sa"), frame(b"sb")].concat();
+        assert_eq!(
+            summary(&client.process(&data)),
+            ["delay 10ms", "forward 0..4", "delay 10ms", "forward 4..8"]
+        );
+        // A message split over two reads is delayed once
+        assert_eq!(
+            summary(&server.process(&data[..3])),
+            ["delay 10ms", "forward 0..3"]
+        );
+        assert_eq!(summary(&server.process(&data[3..4])), ["forward 0..1"]);
+    }
+
+    #[test]
+    fn test_delay_matching() {
+        let rule = DelayRule {
+            fixed: ms(10),
+            matching: Some(Regex::new("sleep").unwrap()),
+            ..DelayRule::default()
+        };
+        let (mut client, mut server) = tampers(TamperConfig {
+            delay: Some(rule),
+            ..TamperConfig::default()
+        });
+        let reply = frame(b"&1 0 1 1 1");
+        assert_eq!(
+            summary(&client.process(&frame(b"sselect 1"))),
+            ["forward 0..11"]
+        );
+        assert_eq!(summary(&server.process(&reply)), ["forward 0..12"]);
+        // The matching query and only the first message of its reply
+        assert_eq!(
+            summary(&client.process(&frame(b"ssleep"))),
+            ["delay 10ms", "forward 0..8"]
+        );
+        assert_eq!(
+            summary(&server.process(&[reply.clone(), reply].concat())),
+            ["delay 10ms", "forward 0..24"]
+        );
+    }
+
+    #[test]
+    fn test_delay_jitter() {
+        let rule = DelayRule {
+            fixed: ms(10),
+            jitter: ms(5),
+            ..DelayRule::default()
+        };
+        let (mut client, _) = tampers(TamperConfig {
+            delay: Some(rule),
+            ..TamperConfig::default()
+        });
+        let data: Vec<u8> = (0..100).flat_map(|_| frame(b"sa")).collect();
+        let delays = |client: &mut Tamper| -> Vec<Duration> {
+            client
+                .process(&data)
+                .into_iter()
+                .filter_map(|a| match a {
+                    Action::Delay(delay) => Some(delay),
+                    _ => None,
+                })
+                .collect()
+        };
+        fastrand::seed(36);
+        let first = delays(&mut client);
+        assert_eq!(first.len(), 100);
+        assert!(first.iter().all(|d| (ms(10)..=ms(15)).contains(d)));
+        assert!(first.iter().any(|d| *d < ms(11)) && first.iter().any(|d| *d > ms(14)));
+        // The same seed gives the same schedule
+        fastrand::seed(36);
+        assert_eq!(delays(&mut client), first);
+    }
+
+    #[test]
+    fn test_bucket() {
+        let mut bucket = Bucket::new(1000);
+        assert_eq!(bucket.chunk(), 100);
+        // A tenth of a second worth of bytes goes at once, the rest on credit
+        assert_eq!(bucket.take(100), Duration::ZERO);
+        let wait = bucket.take(50);
+        assert!(wait > ms(45) && wait <= ms(50), "{wait:?}");
+        // Idle time refills the bucket but no more than its capacity
+        bucket.updated -= Duration::from_secs(1);
+        assert_eq!(bucket.take(100), Duration::ZERO);
+        let wait = bucket.take(100);
+        assert!(wait > ms(95) && wait <= ms(100), "{wait:?}");
+
+        let bucket = Bucket::new(0);
+        assert_eq!(bucket.chunk(), 1);
+    }
+
+    fn chaos(rule: ChaosRule) -> Tamper {
+        let (_, server) = tampers(TamperConfig {
+            chaos: Some(rule),
+            ..TamperConfig::default()
+        });
+        server
+    }
+
+    #[test]
+    fn test_chaos_triggers() {
+        // Three messages of four bytes
+        let data: Vec<u8> = (0..3).flat_map(|_| frame(b"&1")).collect();
+        let after = |after_bytes, after_messages| ChaosRule {
+            after_bytes,
+            after_messages,
+            ..ChaosRule::default()
+        };
+        let mut tamper = chaos(after(None, Some(2)));
+        assert_eq!(summary(&tamper.process(&data)), ["forward 0..8", "close"]);
+        let mut tamper = chaos(after(Some(5), None));
+        assert_eq!(summary(&tamper.process(&data)), ["forward 0..5", "close"]);
+        // Both must hold
+        let mut tamper = chaos(after(Some(6), Some(1)));
+        assert_eq!(summary(&tamper.process(&data)), ["forward 0..6", "close"]);
+        let mut tamper = chaos(after(Some(2), Some(1)));
+        assert_eq!(summary(&tamper.process(&data)), ["forward 0..4", "close"]);
+        // Counting goes on across reads
+        let mut tamper = chaos(after(Some(5), None));
+        assert_eq!(summary(&tamper.process(&data[..4])), ["forward 0..4"]);
+        assert_eq!(
+            summary(&tamper.process(&data[4..])),
+            ["forward 0..1", "close"]
+        );
+        // Fires only once
+        assert_eq!(summary(&tamper.process(&data)), ["forward 0..12"]);
+    }
+
+    #[test]
+    fn test_chaos_actions() {
+        let data: Vec<u8> = (0..3).flat_map(|_| frame(b"&1")).collect();
+        let rule = |action| ChaosRule {
+            action,
+            after_messages: Some(1),
+            ..ChaosRule::default()
+        };
+        let mut tamper = chaos(rule(ChaosAction::Reset));
+        assert_eq!(summary(&tamper.process(&data)), ["forward 0..4", "reset"]);
+        // Cuts the body of the next block in half
+        let mut tamper = chaos(rule(ChaosAction::Truncate));
+        assert_eq!(
+            summary(&tamper.process(&data)),
+            ["forward 0..4", "forward 4..7", "close"]
+        );
+        let mut tamper = chaos(rule(ChaosAction::Stall));
+        assert_eq!(summary(&tamper.process(&data)), ["forward 0..4"]);
+        assert!(tamper.process(&data).is_empty());
+    }
+
+    #[test]
+    fn test_chaos_query() {
+        let (mut client, mut server) = tampers(TamperConfig {
+            chaos: Some(ChaosRule {
+                query: Some(Regex::new("(?i)drop").unwrap()),
+                after_bytes: Some(1),
+                ..ChaosRule::default()
+            }),
+            ..TamperConfig::default()
+        });
+        let reply = frame(b"&3");
+        client.process(&frame(b"sselect 1"));
+        assert_eq!(summary(&server.process(&reply)), ["forward 0..4"]);
+        client.process(&frame(b"sDROP TABLE t"));
+        // Counted from the start of the reply
+        assert_eq!(summary(&server.process(&reply)), ["forward 0..1", "close"]);
+    }
+
+    #[test]
+    fn test_chaos_probability() {
+        let message = frame(b"&1");
+        // Which of a thousand connections lose their first message
+        let lottery = || -> Vec<bool> {
+            (0..1000)
+                .map(|_| {
+                    let mut tamper = chaos(ChaosRule {
+                        probability: Some(0.25),
+                        ..ChaosRule::default()
+                    });
+                    summary(&tamper.process(&message)) == ["close"]
+                })
+                .collect()
+        };
+        fastrand::seed(38);
+        let fired = lottery();
+        let count = fired.iter().filter(|&&f| f).count();
+        assert!((200..300).contains(&count), "{count}");
+        // The same seed gives the same outcome
+        fastrand::seed(38);
+        assert_eq!(lottery(), fired);
+    }
+
+    /// What is sent on after tampering with the data.
+    fn sent(data: &[u8], actions: &[Action]) -> Vec<u8> {
+        let mut sent = vec![];
+        for action in actions {
+            match action {
+                Action::Forward(range) => sent.extend_from_slice(&data[range.clone()]),
+                Action::Send(bytes) => sent.extend_from_slice(bytes),
+                _ => {}
+            }
+        }
+        sent
+    }
+
+    fn corrupt(modes: Vec<CorruptMode>, probability: f64) -> Tamper {
+        let (_, server) = tampers(TamperConfig {
+            corrupt: Some(Corruption { modes, probability }),
+            ..TamperConfig::default()
+        });
+        server
+    }
+
+    #[test]
+    fn test_corrupt_modes() {
+        fastrand::seed(39);
+        let data = frame(b"&ok hello");
+        let header = u16::from_le_bytes([data[0], data[1]]);
+        let damaged = |mode| {
+            let mut tamper = corrupt(vec![mode], 1.0);
+            sent(&data, &tamper.process(&data))
+        };
+
+        let out = damaged(CorruptMode::Length);
+        let wrong = u16::from_le_bytes([out[0], out[1]]);
+        assert_ne!(wrong >> 1, header >> 1);
+        assert!((wrong >> 1).abs_diff(header >> 1) <= 16);
+        assert_eq!(wrong & 1, 1);
+        assert_eq!(out[2..], data[2..]);
+
+        let out = damaged(CorruptMode::NoLast);
+        assert_eq!(out[..2], (header & !1).to_le_bytes());
+        assert_eq!(out[2..], data[2..]);
+
+        // One bit of one byte of the body
+        let out = damaged(CorruptMode::BitFlip);
+        assert_eq!(out.len(), data.len());
+        assert_eq!(out[..2], data[..2]);
+        let flipped: u32 = out
+            .iter()
+            .zip(&data)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert_eq!(flipped, 1);
+
+        assert_eq!(damaged(CorruptMode::NoPrompt), b"");
+    }
+
+    #[test]
+    fn test_corrupt_positions() {
+        // Only the block that ends the message can lose its flag or be dropped
+        let first = [b'x'; BLOCKSIZE];
+        let data = frame(&[&first[..], b"y"].concat());
+        for mode in [CorruptMode::NoLast, CorruptMode::NoPrompt] {
+            let mut tamper = corrupt(vec![mode], 1.0);
+            let out = sent(&data, &tamper.process(&data));
+            assert_eq!(out[..BLOCKSIZE + 2], data[..BLOCKSIZE + 2]);
+            assert_ne!(out[BLOCKSIZE + 2..], data[BLOCKSIZE + 2..]);
+        }
+        // Flips bits all over the body, never in the header
+        fastrand::seed(39);
+        let mut positions = vec![];
+        for _ in 0..200 {
+            let data = frame(&[b'x'; 100]);
+            let mut tamper = corrupt(vec![CorruptMode::BitFlip], 1.0);
+            let out = sent(&data, &tamper.process(&data));
+            positions.extend((0..data.len()).filter(|&i| out[i] != data[i]));
+        }
+        assert_eq!(positions.len(), 200);
+        assert!(positions.iter().all(|&i| i >= 2));
+        assert!(positions.contains(&2) || positions.contains(&3));
+        assert!(positions.contains(&100) || positions.contains(&101));
+        // Headers split over two reads are left alone
+        let data = frame(b"&ok");
+        let mut tamper = corrupt(vec![CorruptMode::NoLast], 1.0);
+        assert_eq!(sent(&data[..1], &tamper.process(&data[..1])), data[..1]);
+        assert_eq!(sent(&data[1..], &tamper.process(&data[1..])), data[1..]);
+    }
+
+    #[test]
+    fn test_corrupt_probability() {
+        let data: Vec<u8> = (0..1000).flat_map(|_| frame(b"&1")).collect();
+        let damaged = || {
+            let mut tamper = corrupt(vec![CorruptMode::NoLast], 0.1);
+            let out = sent(&data, &tamper.process(&data));
+            (0..1000)
+                .filter(|i| out[i * 4] != data[i * 4])
+                .collect::<Vec<_>>()
+        };
+        fastrand::seed(39);
+        let blocks = damaged();
+        assert!((50..150).contains(&blocks.len()), "{}", blocks.len());
+        fastrand::seed(39);
+        assert_eq!(damaged(), blocks);
+        assert_eq!(
+            sent(
+                &data,
+                &corrupt(vec![CorruptMode::Length], 0.0).process(&data)
+            ),
+            data
+        );
+    }
+
+    #[test]
+    fn test_pipelined_replies() {
+        let (mut client, mut server) = tampers(TamperConfig {
+            inject: Some(InjectRule {
+                matching: Regex::new("fail").unwrap(),
+                sqlstate: "42000".to_string(),
+                message: "injected".to_string(),
+            }),
+            ..TamperConfig::default()
+        });
+        let error = frame(b"!42000!injected\n");
+        // What the pump does with the replies
+        let pass_on = |server: &mut Tamper, actions: Vec<Action>| {
+            for action in actions {
+                if let Action::Reply(reply) = action {
+                    server.queue(reply);
+                }
+            }
+        };
+        // The challenge is not an answer
+        let challenge = frame(b"challenge");
+        assert_eq!(summary(&server.process(&challenge)), ["forward 0..11"]);
+
+        // Two queries in one read, the proxy answers the second
+        let queries = [frame(b"sselect 1"), frame(b"sfail")].concat();
+        let actions = client.process(&queries);
+        assert_eq!(summary(&actions), ["send 11", "reply 18"]);
+        pass_on(&mut server, actions);
+        // Only after the server has answered the first
+        assert!(server.replies().is_empty());
+        let answer = frame(b"&1 0 1 1 1");
+        let actions = server.process(&answer);
+        assert_eq!(summary(&actions), ["forward 0..12", "send 18"]);
+        assert!(server.replies().is_empty());
+
+        // The other way round the error can go at once
+        let queries = [frame(b"sfail"), frame(b"sselect 1")].concat();
+        let actions = client.process(&queries);
+        assert_eq!(summary(&actions), ["reply 18", "send 11"]);
+        pass_on(&mut server, actions);
+        assert_eq!(server.replies(), [error]);
+        assert_eq!(summary(&server.process(&answer)), ["forward 0..12"]);
+    }
+
+    #[test]
+    fn test_upper_case_sql() {
+        let (mut client, _) = tampers(TamperConfig {
+            inject: Some(InjectRule {
+                matching: Regex::new("^fail").unwrap(),
+                sqlstate: "42000".to_string(),
+                message: "injected".to_string(),
+            }),
+            rewrite: vec![RewriteRule::literal("t1", "t2".to_string())],
+            firewall: Some(Firewall {
+                read_only: true,
+                ..Firewall::default()
+            }),
+            ..TamperConfig::default()
+        });
+        let reply = |actions: Vec<Action>| -> String {
+            match &actions[..] {
+                [.., Action::Reply(reply)] => String::from_utf8_lossy(&reply.data[2..]).into(),
+                _ => panic!("no reply: {actions:?}"),
+            }
+        };
+        let sent = |actions: Vec<Action>| -> Vec<u8> {
+            match &actions[..] {
+                [.., Action::Send(data)] => data.clone(),
+                _ => panic!("not sent: {actions:?}"),
+            }
+        };
+        for prefix in ["s", "S"] {
+            let message = |sql: &str| frame(format!("{prefix}{sql}").as_bytes());
+            let blocked = reply(client.process(&message("delete from t1")));
+            assert!(
+                blocked.starts_with("!42000!blocked by the proxy"),
+                "{blocked}"
+            );
+            assert_eq!(reply(client.process(&message("fail"))), "!42000!injected\n");
+            let rewritten = sent(client.process(&message("select * from t1")));
+            assert_eq!(rewritten, message("select * from t2"));
+        }
+    }
+}
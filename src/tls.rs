@@ -0,0 +1,344 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+
+/// How the certificate presented by the destination server is checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verify {
+    /// Check the certificate chain and the host name
+    Full,
+    /// Accept any certificate. Only useful for debugging.
+    None,
+}
+
+/// Settings for connecting to the destination over TLS,
+/// collected from the command line.
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub ca_file: Option<PathBuf>,
+    pub verify: Verify,
+    pub sni: Option<String>,
+    pub send_sni: bool,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        TlsSettings {
+            ca_file: None,
+            verify: Verify::Full,
+            sni: None,
+            send_sni: true,
+            client_cert: None,
+            client_key: None,
+        }
+    }
+}
+
+impl TlsSettings {
+    pub fn build(&self) -> io::Result<TlsConnector> {
+        let builder = ClientConfig::builder();
+        let builder = match self.verify {
+            Verify::Full => builder.with_root_certificates(self.root_store()?),
+            Verify::None => {
+                let provider = builder.crypto_provider().clone();
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+            }
+        };
+        let mut config = match (&self.client_cert, &self.client_key) {
+            (None, None) => builder.with_no_client_auth(),
+            (None, Some(_)) => {
+                return Err(invalid_input("client key given without certificate".into()))
+            }
+            (Some(cert), key) => {
+                let certs = load_certs(cert)?;
+                let key = load_key(key.as_deref().unwrap_or(cert))?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .map_err(|e| invalid_input(format!("client certificate: {e}")))?
+            }
+        };
+        config.enable_sni = self.send_sni;
+
+        Ok(TlsConnector {
+            config: Arc::new(config),
+            server_name: self.sni.clone(),
+        })
+    }
+
+    fn root_store(&self) -> io::Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        if let Some(ca_file) = &self.ca_file {
+            for cert in load_certs(ca_file)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| invalid_input(format!("{}: {e}", ca_file.display())))?;
+            }
+        } else {
+            // Like most TLS clients, skip the certificates that fail to parse
+            let native = rustls_native_certs::load_native_certs();
+            roots.add_parsable_certificates(native.certs);
+            if roots.is_empty() {
+                return Err(invalid_input(
+                    "no system CA certificates found, use --tls-ca".into(),
+                ));
+            }
+        }
+        Ok(roots)
+    }
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|it| it.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid_input(format!("{}: {e}", path.display())))?;
+    if certs.is_empty() {
+        let msg = format!("{}: no certificates found", path.display());
+        return Err(invalid_input(msg));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| invalid_input(format!("{}: {e}", path.display())))
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Ready-to-use client configuration, cheap to clone.
+#[derive(Debug, Clone)]
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+    server_name: Option<String>,
+}
+
+impl TlsConnector {
+    /// Perform the TLS handshake on `sock` and split the result into
+    /// a reading and a writing half.
+    ///
+    /// Unless overridden with --tls-sni, `host` is used both for SNI and
+    /// for verifying the server certificate.
+    pub fn connect(&self, host: &str, mut sock: TcpStream) -> io::Result<(TlsReader, TlsWriter)> {
+        let name = self.server_name.as_deref().unwrap_or(host);
+        let name = match name.parse::<IpAddr>() {
+            Ok(ip) => ServerName::IpAddress(ip.into()),
+            Err(_) => ServerName::try_from(name.to_string())
+                .map_err(|e| invalid_input(format!("invalid TLS server name {name:?}: {e}")))?,
+        };
+        let mut conn = ClientConnection::new(Arc::clone(&self.config), name).map_err(tls_error)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+
+        let state = Arc::new(Mutex::new(conn));
+        let write_lock = Arc::new(Mutex::new(()));
+        let reader = TlsReader {
+            state: Arc::clone(&state),
+            write_lock: Arc::clone(&write_lock),
+            sock: sock.try_clone()?,
+            raw: vec![],
+            eof: false,
+        };
+        let writer = TlsWriter {
+            state,
+            write_lock,
+            sock,
+        };
+        Ok((reader, writer))
+    }
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("TLS: {e}"))
+}
+
+const RAW_BUFSIZE: usize = 16 * 1024;
+
+// The reading and the writing half share the rustls state. The state is only
+// locked while encrypting or decrypting, never while blocked on the socket,
+// so one direction cannot stall the other. Records produced by either half
+// wait in the rustls state until the holder of the write lock sends them,
+// which keeps them in order on the wire. The reader never waits for the
+// write lock: the writer may be blocked on the socket holding it, and the
+// peer may only start reading again once we have read what it sent.
+
+pub struct TlsReader {
+    state: Arc<Mutex<ClientConnection>>,
+    write_lock: Arc<Mutex<()>>,
+    sock: TcpStream,
+    /// Received from the socket but not yet accepted by rustls
+    raw: Vec<u8>,
+    eof: bool,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.state.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                other => return other,
+            }
+            if self.raw.is_empty() {
+                if self.eof {
+                    return Ok(0);
+                }
+                let mut raw = [0u8; RAW_BUFSIZE];
+                let n = self.sock.read(&mut raw)?;
+                self.eof = n == 0;
+                self.raw.extend_from_slice(&raw[..n]);
+            }
+            self.receive()?;
+        }
+    }
+}
+
+impl TlsReader {
+    /// Pass rustls as much of the raw data as it accepts. The rest waits
+    /// until the plaintext has been read, otherwise its buffer overflows.
+    fn receive(&mut self) -> io::Result<()> {
+        let mut conn = self.state.lock().unwrap();
+        // An empty slice tells rustls the peer closed the connection
+        let mut data = &self.raw[..];
+        let n = conn.read_tls(&mut data)?;
+        let processed = conn.process_new_packets();
+        let wants_write = conn.wants_write();
+        drop(conn);
+        self.raw.drain(..n);
+        if wants_write {
+            // Alerts, key updates and the like
+            self.flush_records()?;
+        }
+        processed.map(drop).map_err(tls_error)
+    }
+
+    fn flush_records(&mut self) -> io::Result<()> {
+        // If the writer is busy, it sends them when it is done
+        let Ok(_guard) = self.write_lock.try_lock() else {
+            return Ok(());
+        };
+        write_pending(&self.state, &mut self.sock)
+    }
+
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.sock.shutdown(Shutdown::Read)
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+}
+
+pub struct TlsWriter {
+    state: Arc<Mutex<ClientConnection>>,
+    write_lock: Arc<Mutex<()>>,
+    sock: TcpStream,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = {
+            let _guard = self.write_lock.lock().unwrap();
+            let n = self.state.lock().unwrap().writer().write(buf)?;
+            write_pending(&self.state, &mut self.sock)?;
+            n
+        };
+        // The reader may have left records while we held the lock
+        while self.state.lock().unwrap().wants_write() {
+            let _guard = self.write_lock.lock().unwrap();
+            write_pending(&self.state, &mut self.sock)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+impl TlsWriter {
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        {
+            let _guard = self.write_lock.lock().unwrap();
+            self.state.lock().unwrap().send_close_notify();
+            write_pending(&self.state, &mut self.sock)?;
+        }
+        self.sock.shutdown(Shutdown::Write)
+    }
+}
+
+/// Send the records rustls has ready. Only call this holding the write lock.
+fn write_pending(state: &Mutex<ClientConnection>, sock: &mut TcpStream) -> io::Result<()> {
+    let mut records = vec![];
+    {
+        let mut conn = state.lock().unwrap();
+        while conn.wants_write() {
+            conn.write_tls(&mut records)?;
+        }
+    }
+    sock.write_all(&records)
+}
+
+/// Certificate verifier for --tls-no-verify. Signatures are still checked
+/// so the handshake itself is sound, but any certificate is accepted.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}